@@ -22,11 +22,17 @@ module.exports = async function (provider: anchor.AnchorProvider) {
   console.log("BlankonState pub: ", blankonState.publicKey.toBase58());
   console.log("BlankonState prv: ", blankonState.secretKey.toString());
 
+  // 20% of liquidation penalties go to the insurance fund. The deployment runs as a paper
+  // trading game with a $10,000 starting balance and $1,000 daily faucet top-ups.
   const tx = await program.methods
     .initialize(
-      new anchor.web3.PublicKey("2uPQGpm8X4ZkxMHxrAW1QuhXcse1AHEgPih6Xp9NuEWW"),
-      new anchor.web3.PublicKey("7UVimffxr9ow1uXYxsr4LHAcV58mLzhmwaeKvJ1pjLiE"),
-      new anchor.web3.PublicKey("4cSM2e6rvbGQUFiJbqytoVMi5GgghSMr8LwVrT9VPSPo")
+      new anchor.BN(2_000),
+      { paper: {} },
+      {
+        startingBalance: new anchor.BN(10_000_000_000),
+        faucetAmount: new anchor.BN(1_000_000_000),
+        faucetCooldown: new anchor.BN(86_400),
+      }
     )
     .accounts({
       blankonState: blankonState.publicKey,
@@ -35,4 +41,62 @@ module.exports = async function (provider: anchor.AnchorProvider) {
     .signers([blankonState])
     .rpc();
   console.log("Your transaction signature", tx);
+
+  // Gold, SOL and BTC markets
+  const markets = [
+    ["2uPQGpm8X4ZkxMHxrAW1QuhXcse1AHEgPih6Xp9NuEWW", "765d2ba906dbc32ca17cc11f5310a89e9ee1f6420508c63861f2f8ba4ee34bb2"],
+    ["7UVimffxr9ow1uXYxsr4LHAcV58mLzhmwaeKvJ1pjLiE", "ef0d8b6fda2ceba41da15d4095d1da392a0d2f8ed0c6c7bc0f4cfac8c280b56d"],
+    ["4cSM2e6rvbGQUFiJbqytoVMi5GgghSMr8LwVrT9VPSPo", "e62df6c8b4a85fe1a67db44dc12de5db330f7ac66b72dc658afedf0f4a415b43"],
+  ];
+
+  // 10% initial margin, 5% maintenance margin, 10x leverage, 1% max funding rate, 2.5% liquidation fee,
+  // 10% buffer above maintenance margin and at most half of a position closed per liquidation
+  const params = {
+    initialMarginRequirement: new anchor.BN(1_000),
+    maintenanceMarginRequirement: new anchor.BN(500),
+    maxLeverage: 10,
+    skewScale: new anchor.BN(1_000_000_000),
+    maxFundingRate: new anchor.BN(100),
+    liquidationFee: new anchor.BN(250),
+    liquidationBuffer: new anchor.BN(1_000),
+    maxLiquidationFraction: new anchor.BN(5_000),
+    // Reject oracle prices older than a minute
+    maxPriceAgeSecs: new anchor.BN(60),
+    // Refuse opens while the confidence interval is wider than 1% of the price
+    maxConfidenceRatio: new anchor.BN(100),
+    // Reject trades while Pyth and the fallback price disagree by more than 2%
+    maxOracleDeviation: new anchor.BN(200),
+    // Halt a market for 15 minutes when its price moves more than 10% within a minute
+    priceBand: new anchor.BN(1_000),
+    priceBandWindowSecs: new anchor.BN(60),
+    circuitBreakerCooldownSecs: new anchor.BN(900),
+    // Average prices over the last hour
+    twapWindowSecs: new anchor.BN(3_600),
+    // Pay 0.10 from collected trading fees for each due funding crank
+    fundingCrankReward: new anchor.BN(100_000),
+    // Let skew move the funding rate by up to 3% per day instead of setting it directly
+    fundingModel: { velocity: {} },
+    maxFundingVelocity: new anchor.BN(300),
+    // Only used by premium index funding: 0.01% interest per interval, clamped at 0.05%
+    interestRate: new anchor.BN(1),
+    premiumClamp: new anchor.BN(5),
+    // Charge 0.02% on trades that reduce skew and 0.05% on trades that increase it
+    makerFee: new anchor.BN(2),
+    takerFee: new anchor.BN(5),
+    // Send 20% of trading fees to the insurance fund
+    insuranceFeeShare: new anchor.BN(2_000),
+    // Cap positions at 1,000,000 units before leverage
+    maxPositionSize: new anchor.BN(1_000_000_000_000),
+  };
+
+  for (const [pythPriceAccount, feedId] of markets) {
+    const marketTx = await program.methods
+      .addMarket(new anchor.web3.PublicKey(pythPriceAccount), Array.from(Buffer.from(feedId, "hex")), params)
+      .accounts({
+        blankonState: blankonState.publicKey,
+        admin: provider.wallet.publicKey,
+      })
+      .rpc();
+    console.log("Market added", marketTx);
+  }
 };
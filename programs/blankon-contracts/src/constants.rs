@@ -1,21 +1,30 @@
-// Initial balance for new users ($10,000 with 6 decimal places)
-pub const INITIAL_BALANCE: u64 = 10_000_000_000;
+// Upper bound for a market's configurable max leverage
+pub const MAX_LEVERAGE_LIMIT: u8 = 100;
 
-// Initial and maintenance margin requirements (as percentages with 4 decimal places)
-pub const INITIAL_MARGIN_REQUIREMENT: u64 = 1_000; // 10% (0.1 * 10000)
-pub const MAINTENANCE_MARGIN_REQUIREMENT: u64 = 500; // 5% (0.05 * 10000)
-
-// Maximum leverage allowed
-pub const MAX_LEVERAGE: u8 = 10;
+// Maximum number of open positions per user account
+pub const MAX_POSITIONS: usize = 8;
 
 // Funding index precision
 pub const FUNDING_INDEX_DECIMALS: u64 = 1_000_000_000_000; // 12 decimal places
 
-// Skew scale for price impact calculations
-pub const SKEW_SCALE: u64 = 1_000_000_000; // 1 billion
+// Socialized loss index precision
+pub const SOCIALIZED_LOSS_INDEX_DECIMALS: u128 = 1_000_000_000_000; // 12 decimal places
+
+// Number of accumulator checkpoints kept per market for the TWAP
+pub const TWAP_OBSERVATIONS: usize = 8;
+
+// Funding updates kept per market, a day of hourly cranks
+pub const FUNDING_HISTORY_LEN: usize = 24;
+
+// Most funding records returned by one history view, to fit the 1024 byte return data limit
+pub const FUNDING_HISTORY_PAGE_SIZE: usize = 16;
 
 // Decimal precision for prices (6 decimals)
 pub const PRICE_DECIMALS: u128 = 1_000_000;
+pub const PRICE_EXPONENT: i32 = -6; // Oracle exponent matching PRICE_DECIMALS
+
+// Decimals of the collateral mint, matching the 6 decimal USD balances
+pub const COLLATERAL_DECIMALS: u8 = 6;
 
 // Decimal precision for percentages (4 decimals)
 pub const PERCENTAGE_DECIMALS: u64 = 10_000;
@@ -23,5 +32,10 @@ pub const PERCENTAGE_DECIMALS: u64 = 10_000;
 // Funding related constants
 pub const FUNDING_INTERVAL: i64 = 3600; // 1 hour in seconds
 pub const SECONDS_IN_DAY: u64 = 86400; // 24 hours in seconds
-pub const MAX_FUNDING_RATE: u64 = 100; // 1% with 4 decimal places (0.0001 * 10000)
-pub const TIME_FACTOR_DECIMALS: u64 = 1_000_000; // 6 decimal places for time factor precision
+pub const MAX_FUNDING_RATE_LIMIT: u64 = 1_000; // 10% upper bound for a market's max funding rate
+
+// Trading fee related constants
+pub const MAX_TRADING_FEE_LIMIT: u64 = 100; // 1% upper bound for a market's trading fees
+pub const FEE_TIER_COUNT: usize = 4; // Volume tiers in the fee discount table
+pub const VOLUME_WINDOW_DAYS: usize = 30; // Daily buckets summed into a user's rolling volume
+pub const REFERRAL_CODE_LEN: usize = 32; // Referral codes are fixed size byte strings
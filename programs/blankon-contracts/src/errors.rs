@@ -31,4 +31,55 @@ pub enum DErrorCode {
 
     #[msg("Math overflow")]
     MathOverflow,
+
+    #[msg("Invalid market account")]
+    InvalidMarketAccount,
+
+    #[msg("Maximum number of open positions reached")]
+    TooManyPositions,
+
+    #[msg("Invalid market parameters")]
+    InvalidMarketParams,
+
+    #[msg("Account is above maintenance margin")]
+    NotLiquidatable,
+
+    #[msg("Invalid protocol configuration")]
+    InvalidConfiguration,
+
+    #[msg("Invalid collateral mint")]
+    InvalidCollateralMint,
+
+    #[msg("Invalid amount")]
+    InvalidAmount,
+
+    #[msg("Not available in this trading mode")]
+    InvalidTradingMode,
+
+    #[msg("Faucet top-up is still cooling down")]
+    FaucetCooldown,
+
+    #[msg("Oracle price is too old")]
+    StaleOraclePrice,
+
+    #[msg("Oracle confidence interval is too wide")]
+    OracleConfidenceTooWide,
+
+    #[msg("Primary and fallback oracle prices deviate too much")]
+    OracleDeviationTooLarge,
+
+    #[msg("Market is halted by the circuit breaker")]
+    MarketHalted,
+
+    #[msg("Referral code is invalid or not the user's referrer")]
+    InvalidReferralCode,
+
+    #[msg("Fill price is worse than the acceptable price")]
+    PriceSlippageExceeded,
+
+    #[msg("Order expired before it was executed")]
+    OrderExpired,
+
+    #[msg("Position size exceeds the market's maximum")]
+    PositionTooLarge,
 }
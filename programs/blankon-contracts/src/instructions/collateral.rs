@@ -0,0 +1,255 @@
+use crate::constants::*;
+use crate::errors::*;
+use crate::instructions::{accrue_funding, load_markets, settle_funding};
+use crate::math::*;
+use crate::oracle::*;
+use crate::state::*;
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{
+    transfer_checked, Mint, TokenAccount, TokenInterface, TransferChecked,
+};
+
+#[derive(Accounts)]
+pub struct InitializeVault<'info> {
+    #[account(
+        mut,
+        constraint = blankon_state.admin == admin.key() @ DErrorCode::UnauthorizedAccess,
+        constraint = blankon_state.mode == TradingMode::Collateralized @ DErrorCode::InvalidTradingMode
+    )]
+    pub blankon_state: Account<'info, BlankonState>,
+
+    #[account(
+        mint::token_program = token_program,
+        constraint = collateral_mint.decimals == COLLATERAL_DECIMALS @ DErrorCode::InvalidCollateralMint
+    )]
+    pub collateral_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        init,
+        payer = admin,
+        seeds = [b"vault", blankon_state.key().as_ref()],
+        bump,
+        token::mint = collateral_mint,
+        token::authority = vault,
+        token::token_program = token_program
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct TransferCollateral<'info> {
+    pub blankon_state: Account<'info, BlankonState>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"user-account",
+            blankon_state.key().as_ref(),
+            user.key().as_ref()
+        ],
+        bump,
+        constraint = user_account.owner == user.key() @ DErrorCode::UnauthorizedAccess
+    )]
+    pub user_account: Account<'info, UserAccount>,
+
+    pub user: Signer<'info>,
+
+    #[account(address = blankon_state.collateral_mint @ DErrorCode::InvalidCollateralMint)]
+    pub collateral_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        token::mint = collateral_mint,
+        token::authority = user,
+        token::token_program = token_program
+    )]
+    pub user_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault", blankon_state.key().as_ref()],
+        bump = blankon_state.vault_bump
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    // remaining_accounts (withdraw only): [market, pyth_price_account] pair for each open position,
+    // followed by the market's fallback_price_account if it has one, with every market writable
+}
+
+pub fn initialize_vault_handler(ctx: Context<InitializeVault>) -> Result<()> {
+    let blankon_state = &mut ctx.accounts.blankon_state;
+    blankon_state.collateral_mint = ctx.accounts.collateral_mint.key();
+    blankon_state.vault_bump = ctx.bumps.vault;
+
+    msg!(
+        "Collateral vault {} initialized for mint {}",
+        ctx.accounts.vault.key(),
+        blankon_state.collateral_mint
+    );
+
+    Ok(())
+}
+
+pub fn deposit_handler(ctx: Context<TransferCollateral>, amount: u64) -> Result<()> {
+    require!(amount > 0, DErrorCode::InvalidAmount);
+
+    transfer_checked(
+        CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            TransferChecked {
+                from: ctx.accounts.user_token_account.to_account_info(),
+                mint: ctx.accounts.collateral_mint.to_account_info(),
+                to: ctx.accounts.vault.to_account_info(),
+                authority: ctx.accounts.user.to_account_info(),
+            },
+        ),
+        amount,
+        ctx.accounts.collateral_mint.decimals,
+    )?;
+
+    let user_account = &mut ctx.accounts.user_account;
+    user_account.balance = user_account
+        .balance
+        .checked_add(amount)
+        .ok_or(DErrorCode::MathOverflow)?;
+
+    msg!(
+        "Deposited {} collateral, new balance {}",
+        amount,
+        user_account.balance
+    );
+
+    Ok(())
+}
+
+pub fn withdraw_handler<'info>(
+    ctx: Context<'_, '_, 'info, 'info, TransferCollateral<'info>>,
+    amount: u64,
+) -> Result<()> {
+    require!(amount > 0, DErrorCode::InvalidAmount);
+
+    let mut markets = load_markets(&ctx.accounts.blankon_state.key(), ctx.remaining_accounts)?;
+    let user_account = &mut ctx.accounts.user_account;
+
+    // Accrue and settle pending funding so the check sees the up-to-date balance. Equity is
+    // only as good as the prices it is marked at, so a price outside a market's band halts it
+    // and skips the withdrawal, as it would skip a trade.
+    let current_time = Clock::get()?.unix_timestamp;
+    let mut spot_prices = Vec::with_capacity(markets.len());
+    let mut halted = false;
+    for loaded in markets.iter_mut() {
+        accrue_funding(&mut loaded.market, current_time)?;
+        settle_funding(&loaded.market, user_account)?;
+
+        let spot_price = get_oracle_price(
+            &loaded.market,
+            &*loaded.pyth_price_account,
+            loaded.fallback_price_account.as_deref(),
+        )?
+        .price;
+        halted |= !loaded.market.check_price_band(spot_price, current_time)?;
+        spot_prices.push(spot_price);
+    }
+
+    if halted {
+        for loaded in markets.iter() {
+            loaded.market.exit(&crate::ID)?;
+        }
+        return Ok(());
+    }
+
+    require!(
+        user_account.balance >= amount,
+        DErrorCode::InsufficientBalance
+    );
+
+    // The collateral left after the withdrawal must still cover the initial margin of every
+    // open position, marked at the worse of spot and TWAP as liquidations are
+    let mut equity = (user_account.balance - amount) as i128;
+    let mut initial_margin: u64 = 0;
+
+    for position in user_account.positions.iter() {
+        let index = markets
+            .iter()
+            .position(|loaded| loaded.market.market_index == position.market_index)
+            .ok_or(DErrorCode::InvalidMarketAccount)?;
+        let market = &markets[index].market;
+
+        let base_price = market.margin_price(spot_prices[index], position.size, current_time);
+        let price = calculate_price_from_skew(base_price, market.skew, market.params.skew_scale);
+
+        // Profit already socialized away cannot be withdrawn against
+        let pnl = calculate_pnl(
+            position.size,
+            position.leverage,
+            position.entry_price,
+            price,
+        )?;
+        let pnl = pnl
+            - calculate_socialized_haircut(
+                market.socialized_loss_index(position.size) - position.last_socialized_loss_index,
+                position.size,
+                pnl,
+            ) as i64;
+        equity += position.margin as i128 + pnl as i128;
+
+        initial_margin = initial_margin
+            .checked_add(calculate_margin(
+                position.size,
+                position.leverage,
+                price,
+                market.params.initial_margin_requirement,
+            )?)
+            .ok_or(DErrorCode::MathOverflow)?;
+    }
+
+    require!(
+        equity >= initial_margin as i128,
+        DErrorCode::InsufficientBalance
+    );
+
+    user_account.balance -= amount;
+
+    // Markets loaded from remaining accounts are not persisted automatically. All of them had
+    // funding accrued, so all of them are written back.
+    for loaded in markets.iter() {
+        loaded.market.exit(&crate::ID)?;
+    }
+
+    let blankon_state_key = ctx.accounts.blankon_state.key();
+    let signer_seeds: &[&[&[u8]]] = &[&[
+        b"vault",
+        blankon_state_key.as_ref(),
+        &[ctx.accounts.blankon_state.vault_bump],
+    ]];
+
+    transfer_checked(
+        CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            TransferChecked {
+                from: ctx.accounts.vault.to_account_info(),
+                mint: ctx.accounts.collateral_mint.to_account_info(),
+                to: ctx.accounts.user_token_account.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            },
+            signer_seeds,
+        ),
+        amount,
+        ctx.accounts.collateral_mint.decimals,
+    )?;
+
+    msg!(
+        "Withdrew {} collateral, new balance {}",
+        amount,
+        ctx.accounts.user_account.balance
+    );
+
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use crate::errors::*;
+use crate::state::*;
+use anchor_lang::prelude::*;
+
+#[derive(Accounts)]
+pub struct AddFallbackOracle<'info> {
+    #[account(
+        constraint = blankon_state.admin == admin.key() @ DErrorCode::UnauthorizedAccess
+    )]
+    pub blankon_state: Account<'info, BlankonState>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"market",
+            blankon_state.key().as_ref(),
+            market.market_index.to_le_bytes().as_ref()
+        ],
+        bump = market.bump
+    )]
+    pub market: Account<'info, MarketInfo>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + PushedPrice::LEN,
+        seeds = [b"fallback-oracle", market.key().as_ref()],
+        bump
+    )]
+    pub fallback_price_account: Account<'info, PushedPrice>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct PushPrice<'info> {
+    #[account(
+        mut,
+        constraint = fallback_price_account.authority == authority.key() @ DErrorCode::UnauthorizedAccess
+    )]
+    pub fallback_price_account: Account<'info, PushedPrice>,
+
+    pub authority: Signer<'info>,
+}
+
+pub fn add_fallback_oracle_handler(
+    ctx: Context<AddFallbackOracle>,
+    authority: Pubkey,
+) -> Result<()> {
+    let market = &mut ctx.accounts.market;
+
+    ctx.accounts.fallback_price_account.set_inner(PushedPrice {
+        market: market.key(),
+        authority,
+        price: 0,
+        conf: 0,
+        publish_time: 0, // Stale until the first push
+        bump: ctx.bumps.fallback_price_account,
+    });
+
+    market.fallback_oracle = ctx.accounts.fallback_price_account.key();
+
+    msg!(
+        "Added fallback oracle {} for market {}, pushed by {}",
+        market.fallback_oracle,
+        market.market_index,
+        authority
+    );
+
+    Ok(())
+}
+
+pub fn push_price_handler(ctx: Context<PushPrice>, price: u64, conf: u64) -> Result<()> {
+    require!(price > 0, DErrorCode::InvalidOraclePrice);
+
+    let fallback_price_account = &mut ctx.accounts.fallback_price_account;
+    fallback_price_account.price = price;
+    fallback_price_account.conf = conf;
+    fallback_price_account.publish_time = Clock::get()?.unix_timestamp;
+
+    msg!(
+        "Pushed fallback price for market account {}: price={}, conf={}",
+        fallback_price_account.market,
+        price,
+        conf
+    );
+
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use crate::constants::*;
+use crate::errors::*;
+use crate::state::*;
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{
+    transfer_checked, Mint, TokenAccount, TokenInterface, TransferChecked,
+};
+
+#[derive(Accounts)]
+pub struct UpdateFeeTiers<'info> {
+    #[account(
+        mut,
+        constraint = blankon_state.admin == admin.key() @ DErrorCode::UnauthorizedAccess
+    )]
+    pub blankon_state: Account<'info, BlankonState>,
+
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CollectFees<'info> {
+    // Paper mode fees are synthetic, only collateralized fees can be paid out
+    #[account(
+        mut,
+        constraint = blankon_state.admin == admin.key() @ DErrorCode::UnauthorizedAccess,
+        constraint = blankon_state.mode == TradingMode::Collateralized @ DErrorCode::InvalidTradingMode
+    )]
+    pub blankon_state: Account<'info, BlankonState>,
+
+    pub admin: Signer<'info>,
+
+    #[account(address = blankon_state.collateral_mint @ DErrorCode::InvalidCollateralMint)]
+    pub collateral_mint: InterfaceAccount<'info, Mint>,
+
+    // Receives the collected fees
+    #[account(
+        mut,
+        token::mint = collateral_mint,
+        token::token_program = token_program
+    )]
+    pub fee_destination: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"vault", blankon_state.key().as_ref()],
+        bump = blankon_state.vault_bump
+    )]
+    pub vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+pub fn update_fee_tiers_handler(
+    ctx: Context<UpdateFeeTiers>,
+    fee_tiers: [FeeTier; FEE_TIER_COUNT],
+) -> Result<()> {
+    FeeTier::validate_table(&fee_tiers)?;
+
+    ctx.accounts.blankon_state.fee_tiers = fee_tiers;
+
+    for (tier, fee_tier) in fee_tiers.iter().enumerate() {
+        msg!(
+            "Updated fee tier {}: min_volume={}, discount={}",
+            tier,
+            fee_tier.min_volume,
+            fee_tier.discount
+        );
+    }
+
+    Ok(())
+}
+
+// Pay the whole fee pool out of the collateral vault
+pub fn collect_fees_handler(ctx: Context<CollectFees>) -> Result<()> {
+    let amount = ctx.accounts.blankon_state.fee_pool;
+    require!(amount > 0, DErrorCode::InvalidAmount);
+
+    let blankon_state_key = ctx.accounts.blankon_state.key();
+    let signer_seeds: &[&[&[u8]]] = &[&[
+        b"vault",
+        blankon_state_key.as_ref(),
+        &[ctx.accounts.blankon_state.vault_bump],
+    ]];
+
+    transfer_checked(
+        CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            TransferChecked {
+                from: ctx.accounts.vault.to_account_info(),
+                mint: ctx.accounts.collateral_mint.to_account_info(),
+                to: ctx.accounts.fee_destination.to_account_info(),
+                authority: ctx.accounts.vault.to_account_info(),
+            },
+            signer_seeds,
+        ),
+        amount,
+        ctx.accounts.collateral_mint.decimals,
+    )?;
+
+    let blankon_state = &mut ctx.accounts.blankon_state;
+    blankon_state.fee_pool = 0;
+    blankon_state.total_fees_collected = blankon_state
+        .total_fees_collected
+        .checked_add(amount)
+        .ok_or(DErrorCode::MathOverflow)?;
+
+    msg!(
+        "Collected {} trading fees to {}, total collected {}",
+        amount,
+        ctx.accounts.fee_destination.key(),
+        blankon_state.total_fees_collected
+    );
+
+    Ok(())
+}
@@ -1,69 +1,131 @@
 use crate::constants::*;
 use crate::errors::*;
+use crate::instructions::{load_market, settle_funding};
 use crate::math::*;
+use crate::oracle::*;
 use crate::state::*;
 use anchor_lang::prelude::*;
+use pyth_solana_receiver_sdk::price_update::PriceUpdateV2;
 
 #[derive(Accounts)]
 pub struct CalculateFunding<'info> {
     #[account(mut)]
     pub blankon_state: Account<'info, BlankonState>,
 
-    #[account(constraint = admin.key() == blankon_state.admin @ DErrorCode::UnauthorizedAccess)]
-    pub admin: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [
+            b"market",
+            blankon_state.key().as_ref(),
+            market.market_index.to_le_bytes().as_ref()
+        ],
+        bump = market.bump
+    )]
+    pub market: Account<'info, MarketInfo>,
+
+    // Anyone may crank funding
+    pub cranker: Signer<'info>,
+
+    // Receives the market's crank reward when provided
+    #[account(
+        mut,
+        seeds = [
+            b"user-account",
+            blankon_state.key().as_ref(),
+            cranker.key().as_ref()
+        ],
+        bump,
+        constraint = cranker_account.owner == cranker.key() @ DErrorCode::UnauthorizedAccess
+    )]
+    pub cranker_account: Option<Account<'info, UserAccount>>,
+
+    #[account(
+        mut,
+        seeds = [b"funding-history", market.key().as_ref()],
+        bump = funding_history.bump
+    )]
+    pub funding_history: Box<Account<'info, FundingHistory>>,
+
+    #[account(address = market.pyth_price_account @ DErrorCode::InvalidOracleAccount)]
+    pub pyth_price_account: Account<'info, PriceUpdateV2>,
+
+    // Required when the market has a fallback oracle
+    #[account(address = market.fallback_oracle @ DErrorCode::InvalidOracleAccount)]
+    pub fallback_price_account: Option<Account<'info, PushedPrice>>,
 
     // System clock to check time elapsed
     pub clock: Sysvar<'info, Clock>,
 }
 
-pub fn calculate_funding_handler(ctx: Context<CalculateFunding>) -> Result<()> {
-    let blankon_state = &mut ctx.accounts.blankon_state;
-    let current_time = ctx.accounts.clock.unix_timestamp;
+// Funding rate as of `current_time` and the index increment accrued since last_funding_time,
+// under the market's funding model. Premium index funding averages the premium over the whole
+// funding interval so far, not just since the last trade.
+pub fn calculate_funding_step(market: &MarketInfo, current_time: i64) -> (i128, i128) {
+    let params = &market.params;
+    let time_elapsed = (current_time - market.last_funding_time).max(0);
 
-    // Process each market
-    for (asset_idx, market) in blankon_state.markets.iter_mut().enumerate() {
-        // Check if enough time has passed since last funding (1 hour = 3600 seconds)
-        let time_elapsed = current_time - market.last_funding_time;
-        if time_elapsed < FUNDING_INTERVAL {
-            msg!(
-                "Skipping funding for market {}: not enough time elapsed",
-                asset_idx
-            );
-            continue;
+    match params.funding_model {
+        FundingModel::Skew => {
+            let funding_rate =
+                calculate_funding_rate(market.skew, params.skew_scale, params.max_funding_rate);
+            (
+                funding_rate,
+                funding_rate * time_elapsed as i128 / SECONDS_IN_DAY as i128,
+            )
         }
-
-        // Calculate funding rate based on market skew
-        let funding_rate = calculate_funding_rate(market.skew, SKEW_SCALE, MAX_FUNDING_RATE);
-
-        // Skip if funding rate is zero
-        if funding_rate == 0 {
-            msg!(
-                "Skipping funding for market {}: funding rate is zero",
-                asset_idx
+        FundingModel::Velocity => calculate_velocity_funding(
+            market.funding_rate,
+            calculate_funding_velocity(market.skew, params.skew_scale, params.max_funding_velocity),
+            params.max_funding_rate as i128 * FUNDING_INDEX_DECIMALS as i128
+                / PERCENTAGE_DECIMALS as i128,
+            time_elapsed,
+        ),
+        FundingModel::PremiumIndex => {
+            let interval_elapsed = current_time - market.last_history_time;
+            let average_premium = if interval_elapsed > 0 {
+                market.premium_sum_at(current_time) / interval_elapsed as i128
+            } else {
+                market.premium_last
+            };
+            let funding_rate = calculate_premium_funding_rate(
+                average_premium,
+                params.interest_rate,
+                params.premium_clamp,
+                params.max_funding_rate,
             );
-            continue;
+            (
+                funding_rate,
+                funding_rate * time_elapsed as i128 / SECONDS_IN_DAY as i128,
+            )
         }
+    }
+}
 
-        // Calculate funding index increment
-        // For hourly funding, time factor is 1/24 (assuming 24 hours in a day)
-        let time_factor = time_elapsed as u64 * TIME_FACTOR_DECIMALS / SECONDS_IN_DAY;
+// Bring the market's funding index up to `current_time` at the current skew. Called before
+// anything that reads the index or changes the skew, so each period accrues at the skew that
+// was actually in place.
+pub fn accrue_funding(market: &mut MarketInfo, current_time: i64) -> Result<()> {
+    let time_elapsed = current_time - market.last_funding_time;
+    if time_elapsed <= 0 {
+        return Ok(());
+    }
 
-        // Funding index increment = funding rate * time factor
-        let funding_index_increment = (funding_rate as i128 * time_factor as i128)
-            / (PERCENTAGE_DECIMALS as i128 * TIME_FACTOR_DECIMALS as i128);
+    let (funding_rate, funding_index_increment) = calculate_funding_step(market, current_time);
 
-        // Update global funding index
-        market.global_funding_index = market
-            .global_funding_index
-            .checked_add(funding_index_increment)
-            .ok_or(DErrorCode::MathOverflow)?;
+    // Update global funding index
+    market.global_funding_index = market
+        .global_funding_index
+        .checked_add(funding_index_increment)
+        .ok_or(DErrorCode::MathOverflow)?;
 
-        // Update market's last funding time
-        market.last_funding_time = current_time;
+    // Update market's funding rate and last funding time
+    market.funding_rate = funding_rate;
+    market.last_funding_time = current_time;
 
+    if funding_index_increment != 0 {
         msg!(
             "Global funding updated for market {}: rate={}, index_increment={}, new_index={}",
-            asset_idx,
+            market.market_index,
             funding_rate,
             funding_index_increment,
             market.global_funding_index
@@ -72,3 +134,288 @@ pub fn calculate_funding_handler(ctx: Context<CalculateFunding>) -> Result<()> {
 
     Ok(())
 }
+
+// Accrue funding and append it to the market's history once per FUNDING_INTERVAL. Trades
+// accrue funding as they happen, so the interval runs from the last history record rather
+// than from last_funding_time. Returns whether a record was written.
+pub fn record_funding_history(
+    market: &mut MarketInfo,
+    funding_history: &mut FundingHistory,
+    oracle_price: u64,
+    current_time: i64,
+) -> Result<bool> {
+    if current_time - market.last_history_time < FUNDING_INTERVAL {
+        return Ok(false);
+    }
+
+    accrue_funding(market, current_time)?;
+
+    funding_history.push(FundingRecord {
+        timestamp: current_time,
+        funding_rate: market.funding_rate,
+        funding_index: market.global_funding_index,
+        skew: market.skew,
+        oracle_price,
+    });
+    market.last_history_time = current_time;
+
+    // Start summing premiums for the next interval
+    market.premium_sum = 0;
+    market.premium_last_sample = current_time;
+
+    Ok(true)
+}
+
+pub fn calculate_funding_handler(ctx: Context<CalculateFunding>) -> Result<()> {
+    let market = &mut ctx.accounts.market;
+    let current_time = ctx.accounts.clock.unix_timestamp;
+
+    let oracle_price = get_oracle_price(
+        market,
+        &*ctx.accounts.pyth_price_account,
+        ctx.accounts.fallback_price_account.as_deref(),
+    )?;
+
+    // The crank trips the circuit breaker like trades do, and leaves a halted market alone
+    // until it resumes
+    if market.is_halted(current_time)
+        || !market.check_price_band(oracle_price.price, current_time)?
+    {
+        msg!(
+            "Skipping funding for market {}: halted by the circuit breaker",
+            market.market_index
+        );
+        return Ok(());
+    }
+
+    // Keep the TWAP accumulator fed for quiet markets, rate limited against spamming
+    market.sample_crank_price(oracle_price.price, current_time);
+
+    // Trades accrue funding as they happen, the crank keeps quiet markets current and records
+    // the history of every market
+    if !record_funding_history(
+        market,
+        &mut ctx.accounts.funding_history,
+        oracle_price.price,
+        current_time,
+    )? {
+        msg!(
+            "Skipping funding for market {}: not enough time elapsed",
+            market.market_index
+        );
+        return Ok(());
+    }
+
+    // Pay the cranker out of collected trading fees, as far as they go. The insurance fund is
+    // kept for bad debt.
+    if let Some(cranker_account) = ctx.accounts.cranker_account.as_mut() {
+        let blankon_state = &mut ctx.accounts.blankon_state;
+        let reward = std::cmp::min(market.params.funding_crank_reward, blankon_state.fee_pool);
+
+        if reward > 0 {
+            blankon_state.fee_pool -= reward;
+            cranker_account.balance = cranker_account
+                .balance
+                .checked_add(reward)
+                .ok_or(DErrorCode::MathOverflow)?;
+
+            msg!(
+                "Paid funding crank reward of {} for market {} to {}",
+                reward,
+                market.market_index,
+                cranker_account.owner
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Accounts)]
+pub struct SettleMarketFunding<'info> {
+    pub blankon_state: Account<'info, BlankonState>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"market",
+            blankon_state.key().as_ref(),
+            market.market_index.to_le_bytes().as_ref()
+        ],
+        bump = market.bump
+    )]
+    pub market: Account<'info, MarketInfo>,
+
+    // Funding is owed either way, so anyone may settle it
+    #[account(
+        mut,
+        seeds = [
+            b"user-account",
+            blankon_state.key().as_ref(),
+            user_account.owner.as_ref()
+        ],
+        bump
+    )]
+    pub user_account: Account<'info, UserAccount>,
+}
+
+#[derive(Accounts)]
+pub struct SettleAllFunding<'info> {
+    pub blankon_state: Account<'info, BlankonState>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"user-account",
+            blankon_state.key().as_ref(),
+            user_account.owner.as_ref()
+        ],
+        bump
+    )]
+    pub user_account: Account<'info, UserAccount>,
+    // remaining_accounts: writable market for each open position
+}
+
+// Accrue funding in a single market and settle the user's position there, leaving every other
+// market untouched
+pub fn settle_market_funding_handler(ctx: Context<SettleMarketFunding>) -> Result<()> {
+    accrue_funding(&mut ctx.accounts.market, Clock::get()?.unix_timestamp)?;
+    settle_funding(&ctx.accounts.market, &mut ctx.accounts.user_account)
+}
+
+// Accrue and settle funding for every market passed in, one market at a time
+pub fn settle_all_funding_handler<'info>(
+    ctx: Context<'_, '_, 'info, 'info, SettleAllFunding<'info>>,
+) -> Result<()> {
+    let blankon_state = ctx.accounts.blankon_state.key();
+    let user_account = &mut ctx.accounts.user_account;
+    let current_time = Clock::get()?.unix_timestamp;
+
+    let mut settled: Vec<u16> = Vec::with_capacity(ctx.remaining_accounts.len());
+    for account_info in ctx.remaining_accounts.iter() {
+        let mut market = load_market(&blankon_state, account_info)?;
+
+        // Each market may only appear once so writes cannot overwrite each other
+        require!(
+            !settled.contains(&market.market_index),
+            DErrorCode::InvalidMarketAccount
+        );
+        settled.push(market.market_index);
+
+        accrue_funding(&mut market, current_time)?;
+        settle_funding(&market, user_account)?;
+
+        // Markets loaded from remaining accounts are not persisted automatically
+        market.exit(&crate::ID)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests::market;
+
+    fn funding_history() -> FundingHistory {
+        FundingHistory {
+            market: Pubkey::default(),
+            total_records: 0,
+            records: [FundingRecord::default(); FUNDING_HISTORY_LEN],
+            bump: 0,
+        }
+    }
+
+    #[test]
+    fn crank_records_history_on_traded_markets() {
+        let mut market = market();
+        let mut funding_history = funding_history();
+
+        // A long opened after half an interval, like open_handler does it
+        let opened_at = FUNDING_INTERVAL / 2;
+        accrue_funding(&mut market, opened_at).unwrap();
+        market.total_long_size = 100_000_000;
+        market.skew = 100_000_000;
+        market.sample_premium(100_000_000, opened_at);
+
+        // Trades every ten minutes keep accruing funding before the crank comes around, so
+        // less than an interval has passed since last_funding_time when it does
+        for time in (opened_at..FUNDING_INTERVAL).step_by(600) {
+            accrue_funding(&mut market, time).unwrap();
+        }
+        assert_eq!(market.last_funding_time, FUNDING_INTERVAL - 600);
+
+        assert!(record_funding_history(
+            &mut market,
+            &mut funding_history,
+            100_000_000,
+            FUNDING_INTERVAL
+        )
+        .unwrap());
+        assert_eq!(funding_history.total_records, 1);
+
+        let record = funding_history.records[0];
+        assert_eq!(record.timestamp, FUNDING_INTERVAL);
+        assert_eq!(record.funding_index, market.global_funding_index);
+        assert_eq!(record.skew, 100_000_000);
+        assert!(record.funding_rate > 0);
+
+        // The next record is due an interval later, however often the market trades
+        assert!(!record_funding_history(
+            &mut market,
+            &mut funding_history,
+            100_000_000,
+            2 * FUNDING_INTERVAL - 1
+        )
+        .unwrap());
+        assert_eq!(funding_history.total_records, 1);
+    }
+
+    #[test]
+    fn premium_is_averaged_over_the_whole_interval() {
+        let premium_market = || {
+            let mut market = market();
+            market.params.funding_model = FundingModel::PremiumIndex;
+            market.params.max_funding_rate = 1_000;
+            market.params.interest_rate = 1;
+            market.params.premium_clamp = 5;
+            market.sample_premium(100_000_000, 0);
+            market
+        };
+
+        // No skew for the first half of the interval, then a 0.2% premium from a long skew
+        let mut quiet = premium_market();
+        accrue_funding(&mut quiet, FUNDING_INTERVAL / 2).unwrap();
+        quiet.skew = 2_000_000;
+        quiet.sample_premium(100_000_000, FUNDING_INTERVAL / 2);
+        let premium = quiet.premium_last;
+        assert_eq!(premium, 2_000_000_000);
+
+        // The same market with trades every ten minutes in the second half
+        let mut traded = quiet.clone();
+        for time in (FUNDING_INTERVAL / 2..FUNDING_INTERVAL).step_by(600) {
+            accrue_funding(&mut traded, time).unwrap();
+        }
+
+        for market in [&mut quiet, &mut traded] {
+            assert!(record_funding_history(
+                market,
+                &mut funding_history(),
+                100_000_000,
+                FUNDING_INTERVAL
+            )
+            .unwrap());
+
+            // Both pay on the average premium over the interval, not the premium since their
+            // last trade
+            assert_eq!(
+                market.funding_rate,
+                calculate_premium_funding_rate(premium / 2, 1, 5, 1_000)
+            );
+
+            // The next interval starts summing from scratch
+            assert_eq!(market.premium_sum, 0);
+            assert_eq!(market.premium_last_sample, FUNDING_INTERVAL);
+        }
+    }
+}
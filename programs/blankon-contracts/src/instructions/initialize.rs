@@ -1,3 +1,5 @@
+use crate::constants::*;
+use crate::errors::*;
 use crate::state::*;
 use anchor_lang::prelude::*;
 
@@ -5,6 +7,14 @@ use anchor_lang::prelude::*;
 pub struct Initialize<'info> {
     #[account(init, payer = admin, space = 8 + BlankonState::LEN)]
     pub blankon_state: Account<'info, BlankonState>,
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + InsuranceFund::LEN,
+        seeds = [b"insurance-fund", blankon_state.key().as_ref()],
+        bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
     #[account(mut)]
     pub admin: Signer<'info>,
     pub system_program: Program<'info, System>,
@@ -12,42 +22,53 @@ pub struct Initialize<'info> {
 
 pub fn initialize_handler(
     ctx: Context<Initialize>,
-    gold_pyth_account: Pubkey,
-    sol_pyth_account: Pubkey,
-    btc_pyth_account: Pubkey,
+    insurance_fund_share: u64,
+    mode: TradingMode,
+    paper_config: PaperConfig,
 ) -> Result<()> {
+    require!(
+        insurance_fund_share <= PERCENTAGE_DECIMALS,
+        DErrorCode::InvalidConfiguration
+    );
+
     let blankon_state = &mut ctx.accounts.blankon_state;
     blankon_state.admin = ctx.accounts.admin.key();
 
-    blankon_state.markets[GOLD as usize] = MarketInfo {
-        asset_type: GOLD,
-        pyth_price_account: gold_pyth_account,
-        skew: 0,
-        total_long_size: 0,
-        total_short_size: 0,
-        last_funding_time: Clock::get()?.unix_timestamp,
-        global_funding_index: 0,
-    };
+    // Markets are registered afterwards through add_market
+    blankon_state.market_count = 0;
 
-    blankon_state.markets[SOL as usize] = MarketInfo {
-        asset_type: SOL,
-        pyth_price_account: sol_pyth_account,
-        skew: 0,
-        total_long_size: 0,
-        total_short_size: 0,
-        last_funding_time: Clock::get()?.unix_timestamp,
-        global_funding_index: 0,
-    };
+    blankon_state.insurance_fund_share = insurance_fund_share;
+    blankon_state.cumulative_bad_debt = 0;
+    blankon_state.cumulative_socialized_loss = 0;
 
-    blankon_state.markets[BTC as usize] = MarketInfo {
-        asset_type: BTC,
-        pyth_price_account: btc_pyth_account,
-        skew: 0,
-        total_long_size: 0,
-        total_short_size: 0,
-        last_funding_time: Clock::get()?.unix_timestamp,
-        global_funding_index: 0,
+    // Paper mode hands out synthetic balances, collateralized mode never credits free balance
+    blankon_state.mode = mode;
+    blankon_state.paper_config = match mode {
+        TradingMode::Paper => {
+            paper_config.validate()?;
+            paper_config
+        }
+        TradingMode::Collateralized => PaperConfig {
+            starting_balance: 0,
+            faucet_amount: 0,
+            faucet_cooldown: 0,
+        },
     };
 
+    // Collateral is configured afterwards through initialize_vault
+    blankon_state.collateral_mint = Pubkey::default();
+    blankon_state.vault_bump = 0;
+
+    // Trading fees accumulate as positions are opened and closed
+    blankon_state.fee_pool = 0;
+    blankon_state.total_fees_collected = 0;
+    blankon_state.fee_tiers = [FeeTier::default(); FEE_TIER_COUNT];
+    blankon_state.referral_config = ReferralConfig::default();
+
+    let insurance_fund = &mut ctx.accounts.insurance_fund;
+    insurance_fund.balance = 0;
+    insurance_fund.total_shortfall_covered = 0;
+    insurance_fund.bump = ctx.bumps.insurance_fund;
+
     Ok(())
 }
@@ -0,0 +1,279 @@
+use crate::constants::*;
+use crate::errors::*;
+use crate::instructions::{absorb_shortfall, accrue_funding, load_markets, settle_funding};
+use crate::math::*;
+use crate::oracle::*;
+use crate::state::*;
+use anchor_lang::prelude::*;
+
+#[derive(Accounts)]
+pub struct Liquidate<'info> {
+    #[account(mut)]
+    pub blankon_state: Account<'info, BlankonState>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"user-account",
+            blankon_state.key().as_ref(),
+            user_account.owner.as_ref()
+        ],
+        bump,
+        constraint = user_account.owner != keeper.key() @ DErrorCode::UnauthorizedAccess
+    )]
+    pub user_account: Account<'info, UserAccount>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"user-account",
+            blankon_state.key().as_ref(),
+            keeper.key().as_ref()
+        ],
+        bump,
+        constraint = keeper_account.owner == keeper.key() @ DErrorCode::UnauthorizedAccess
+    )]
+    pub keeper_account: Account<'info, UserAccount>,
+
+    pub keeper: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"insurance-fund", blankon_state.key().as_ref()],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+    // remaining_accounts: [market, pyth_price_account] pair for each open position, followed
+    // by the market's fallback_price_account if it has one, with every market writable
+}
+
+pub fn liquidate_handler<'info>(
+    ctx: Context<'_, '_, 'info, 'info, Liquidate<'info>>,
+    market_index: u16,
+) -> Result<()> {
+    let mut markets = load_markets(&ctx.accounts.blankon_state.key(), ctx.remaining_accounts)?;
+    let user_account = &mut ctx.accounts.user_account;
+
+    // Accrue and settle pending funding so the margin check sees the up-to-date balance
+    let current_time = Clock::get()?.unix_timestamp;
+    for loaded in markets.iter_mut() {
+        accrue_funding(&mut loaded.market, current_time)?;
+        settle_funding(&loaded.market, user_account)?;
+    }
+
+    // Account equity is the free balance plus the locked margin and PnL of every position,
+    // marked at the worse of each market's spot price and TWAP as withdrawals are
+    let mut equity = user_account.balance as i128;
+    let mut maintenance_margin: u64 = 0;
+    let mut exit_base_price = 0;
+
+    for position in user_account.positions.iter() {
+        let loaded = markets
+            .iter()
+            .find(|loaded| loaded.market.market_index == position.market_index)
+            .ok_or(DErrorCode::InvalidMarketAccount)?;
+        let market = &loaded.market;
+
+        let spot_price = get_oracle_price(
+            market,
+            &*loaded.pyth_price_account,
+            loaded.fallback_price_account.as_deref(),
+        )?
+        .price;
+        let base_price = market.margin_price(spot_price, position.size, current_time);
+        let price = calculate_price_from_skew(base_price, market.skew, market.params.skew_scale);
+
+        let pnl = calculate_pnl(
+            position.size,
+            position.leverage,
+            position.entry_price,
+            price,
+        )?;
+        equity += position.margin as i128 + pnl as i128;
+
+        maintenance_margin = maintenance_margin
+            .checked_add(calculate_margin(
+                position.size,
+                position.leverage,
+                price,
+                market.params.maintenance_margin_requirement,
+            )?)
+            .ok_or(DErrorCode::MathOverflow)?;
+
+        // The forced close itself trades at the spot price
+        if position.market_index == market_index {
+            exit_base_price = spot_price;
+        }
+    }
+
+    let position = *user_account
+        .position(market_index)
+        .ok_or(DErrorCode::NoPositionExists)?;
+    let market = &mut markets
+        .iter_mut()
+        .find(|loaded| loaded.market.market_index == market_index)
+        .ok_or(DErrorCode::InvalidMarketAccount)?
+        .market;
+
+    // Liquidation does not bypass the circuit breaker: a price outside the band is suspect and
+    // could make a healthy account look liquidatable. It halts the market instead, and the
+    // liquidation is skipped rather than failed so that the halt persists.
+    if !market.check_price_band(exit_base_price, current_time)? {
+        for loaded in markets.iter() {
+            loaded.market.exit(&crate::ID)?;
+        }
+        return Ok(());
+    }
+    market.record_price(exit_base_price, current_time);
+
+    require!(
+        equity < maintenance_margin as i128,
+        DErrorCode::NotLiquidatable
+    );
+    let params = market.params;
+
+    // Close only as much of the position as needed to restore the account. The close fills
+    // from the skew-adjusted spot price, so that is what its margin and fee are sized at.
+    let exit_price = calculate_price_from_skew(exit_base_price, market.skew, params.skew_scale);
+    let close_fraction = calculate_liquidation_fraction(
+        equity,
+        maintenance_margin,
+        calculate_margin(
+            position.size,
+            position.leverage,
+            exit_price,
+            params.maintenance_margin_requirement,
+        )?,
+        calculate_margin(
+            position.size,
+            position.leverage,
+            exit_price,
+            params.liquidation_fee,
+        )?,
+        params.liquidation_buffer,
+        params.max_liquidation_fraction,
+    );
+
+    let position_size = position.size.unsigned_abs();
+    let close_size = std::cmp::min(
+        (position_size as u128 * close_fraction as u128).div_ceil(PERCENTAGE_DECIMALS as u128)
+            as u64,
+        position_size,
+    );
+    let close_size_signed = if position.size > 0 {
+        close_size as i64
+    } else {
+        -(close_size as i64)
+    };
+
+    // Realize the PnL at the fill price of the forced close, less socialized losses, and
+    // release the margin of the closed part
+    let fill_price = calculate_fill_price(
+        exit_base_price,
+        market.skew,
+        -close_size_signed,
+        params.skew_scale,
+    );
+    let pnl = calculate_pnl(
+        close_size_signed,
+        position.leverage,
+        position.entry_price,
+        fill_price,
+    )?;
+    let pnl = pnl
+        - calculate_socialized_haircut(
+            market.socialized_loss_index(position.size) - position.last_socialized_loss_index,
+            close_size_signed,
+            pnl,
+        ) as i64;
+    let released_margin =
+        (position.margin as u128 * close_size as u128 / position_size as u128) as u64;
+    let liquidation_fee = calculate_margin(
+        close_size_signed,
+        position.leverage,
+        fill_price,
+        params.liquidation_fee,
+    )?;
+
+    if position.size > 0 {
+        market.total_long_size = market
+            .total_long_size
+            .checked_sub(close_size)
+            .ok_or(DErrorCode::MathOverflow)?;
+    } else {
+        market.total_short_size = market
+            .total_short_size
+            .checked_sub(close_size)
+            .ok_or(DErrorCode::MathOverflow)?;
+    }
+
+    market.skew = market.total_long_size as i64 - market.total_short_size as i64;
+    market.sample_premium(exit_base_price, current_time);
+
+    // Whatever collateral is left pays the liquidation penalty first, the user keeps the rest
+    let collateral = user_account.balance as i128 + released_margin as i128 + pnl as i128;
+    let remaining_collateral = collateral.max(0) as u64;
+    let penalty = std::cmp::min(liquidation_fee, remaining_collateral);
+
+    user_account.balance = remaining_collateral - penalty;
+
+    // A share of the penalty goes to the insurance fund, the keeper gets the rest
+    let insurance_fund = &mut ctx.accounts.insurance_fund;
+    let insurance_share = (penalty as u128
+        * ctx.accounts.blankon_state.insurance_fund_share as u128
+        / PERCENTAGE_DECIMALS as u128) as u64;
+    let keeper_reward = penalty - insurance_share;
+
+    insurance_fund.balance = insurance_fund
+        .balance
+        .checked_add(insurance_share)
+        .ok_or(DErrorCode::MathOverflow)?;
+
+    // Losses beyond the account's collateral are absorbed by the insurance fund or socialized
+    let shortfall = (-collateral).max(0) as u64;
+    if shortfall > 0 {
+        absorb_shortfall(
+            &mut ctx.accounts.blankon_state,
+            insurance_fund,
+            market,
+            position.size,
+            shortfall,
+        )?;
+    }
+
+    if close_size == position_size {
+        user_account.remove_position(market_index);
+    } else if let Some(position) = user_account.position_mut(market_index) {
+        position.size -= close_size_signed;
+        position.margin -= released_margin;
+    }
+
+    let keeper_account = &mut ctx.accounts.keeper_account;
+    keeper_account.balance = keeper_account
+        .balance
+        .checked_add(keeper_reward)
+        .ok_or(DErrorCode::MathOverflow)?;
+
+    // Markets loaded from remaining accounts are not persisted automatically. All of them had
+    // funding accrued, so all of them are written back.
+    for loaded in markets.iter() {
+        loaded.market.exit(&crate::ID)?;
+    }
+
+    msg!(
+        "Liquidated {} of {} position in market {} for {}: equity={}, maintenance_margin={}, pnl={}, fill_price={}, keeper_reward={}, insurance_share={}, shortfall={}",
+        close_size,
+        position_size,
+        market_index,
+        user_account.owner,
+        equity,
+        maintenance_margin,
+        pnl,
+        fill_price,
+        keeper_reward,
+        insurance_share,
+        shortfall
+    );
+
+    Ok(())
+}
@@ -0,0 +1,280 @@
+use crate::constants::*;
+use crate::errors::*;
+use crate::instructions::accrue_funding;
+use crate::state::*;
+use anchor_lang::prelude::*;
+use pyth_solana_receiver_sdk::price_update::PriceUpdateV2;
+
+#[derive(Accounts)]
+pub struct AddMarket<'info> {
+    #[account(
+        mut,
+        constraint = blankon_state.admin == admin.key() @ DErrorCode::UnauthorizedAccess
+    )]
+    pub blankon_state: Account<'info, BlankonState>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + MarketInfo::LEN,
+        seeds = [
+            b"market",
+            blankon_state.key().as_ref(),
+            blankon_state.market_count.to_le_bytes().as_ref()
+        ],
+        bump
+    )]
+    pub market: Account<'info, MarketInfo>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + FundingHistory::LEN,
+        seeds = [b"funding-history", market.key().as_ref()],
+        bump
+    )]
+    pub funding_history: Box<Account<'info, FundingHistory>>,
+
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateMarketParams<'info> {
+    #[account(
+        constraint = blankon_state.admin == admin.key() @ DErrorCode::UnauthorizedAccess
+    )]
+    pub blankon_state: Account<'info, BlankonState>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"market",
+            blankon_state.key().as_ref(),
+            market.market_index.to_le_bytes().as_ref()
+        ],
+        bump = market.bump
+    )]
+    pub market: Account<'info, MarketInfo>,
+
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ResumeMarket<'info> {
+    #[account(
+        constraint = blankon_state.admin == admin.key() @ DErrorCode::UnauthorizedAccess
+    )]
+    pub blankon_state: Account<'info, BlankonState>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"market",
+            blankon_state.key().as_ref(),
+            market.market_index.to_le_bytes().as_ref()
+        ],
+        bump = market.bump
+    )]
+    pub market: Account<'info, MarketInfo>,
+
+    pub admin: Signer<'info>,
+}
+
+pub fn add_market_handler(
+    ctx: Context<AddMarket>,
+    pyth_price_account: Pubkey,
+    pyth_feed_id: [u8; 32],
+    params: MarketParams,
+) -> Result<()> {
+    params.validate()?;
+
+    let blankon_state = &mut ctx.accounts.blankon_state;
+    let market_index = blankon_state.market_count;
+
+    ctx.accounts.market.set_inner(MarketInfo {
+        market_index,
+        pyth_price_account,
+        pyth_feed_id,
+        fallback_oracle: Pubkey::default(),
+        skew: 0,
+        total_long_size: 0,
+        total_short_size: 0,
+        last_funding_time: Clock::get()?.unix_timestamp,
+        global_funding_index: 0,
+        funding_rate: 0,
+        premium_sum: 0,
+        premium_last: 0,
+        premium_last_sample: Clock::get()?.unix_timestamp,
+        last_history_time: Clock::get()?.unix_timestamp,
+        long_socialized_loss_index: 0,
+        short_socialized_loss_index: 0,
+        params,
+        bump: ctx.bumps.market,
+        last_accepted_price: 0,
+        last_accepted_price_time: 0,
+        halted: false,
+        halted_at: 0,
+        cumulative_price: 0,
+        twap_last_price: 0,
+        twap_last_update: 0,
+        twap_observations: [TwapObservation::default(); TWAP_OBSERVATIONS],
+        twap_observation_index: 0,
+    });
+
+    let funding_history = &mut ctx.accounts.funding_history;
+    funding_history.market = ctx.accounts.market.key();
+    funding_history.bump = ctx.bumps.funding_history;
+
+    blankon_state.market_count = market_index
+        .checked_add(1)
+        .ok_or(DErrorCode::MathOverflow)?;
+
+    msg!(
+        "Added market {} with oracle {}",
+        market_index,
+        pyth_price_account
+    );
+
+    Ok(())
+}
+
+pub fn update_market_params_handler(
+    ctx: Context<UpdateMarketParams>,
+    params: MarketParams,
+) -> Result<()> {
+    params.validate()?;
+
+    let market = &mut ctx.accounts.market;
+    // Funding accrued so far is owed under the old params
+    accrue_funding(market, Clock::get()?.unix_timestamp)?;
+    market.params = params;
+
+    msg!(
+        "Updated params for market {}: initial_margin={}, maintenance_margin={}, max_leverage={}x, skew_scale={}, max_funding_rate={}, liquidation_fee={}, liquidation_buffer={}, max_liquidation_fraction={}, max_price_age_secs={}, max_confidence_ratio={}, max_oracle_deviation={}, price_band={}, price_band_window_secs={}, circuit_breaker_cooldown_secs={}, twap_window_secs={}, funding_crank_reward={}, funding_model={:?}, max_funding_velocity={}, interest_rate={}, premium_clamp={}, maker_fee={}, taker_fee={}, insurance_fee_share={}, max_position_size={}",
+        market.market_index,
+        params.initial_margin_requirement,
+        params.maintenance_margin_requirement,
+        params.max_leverage,
+        params.skew_scale,
+        params.max_funding_rate,
+        params.liquidation_fee,
+        params.liquidation_buffer,
+        params.max_liquidation_fraction,
+        params.max_price_age_secs,
+        params.max_confidence_ratio,
+        params.max_oracle_deviation,
+        params.price_band,
+        params.price_band_window_secs,
+        params.circuit_breaker_cooldown_secs,
+        params.twap_window_secs,
+        params.funding_crank_reward,
+        params.funding_model,
+        params.max_funding_velocity,
+        params.interest_rate,
+        params.premium_clamp,
+        params.maker_fee,
+        params.taker_fee,
+        params.insurance_fee_share,
+        params.max_position_size
+    );
+
+    Ok(())
+}
+
+pub fn resume_market_handler(ctx: Context<ResumeMarket>) -> Result<()> {
+    let market = &mut ctx.accounts.market;
+    market.resume();
+
+    msg!("Resumed market {}", market.market_index);
+
+    Ok(())
+}
+
+/// Loads a market account passed outside of the `Accounts` struct (e.g. via
+/// `remaining_accounts`) and verifies it is the market PDA of `blankon_state`.
+pub fn load_market<'info>(
+    blankon_state: &Pubkey,
+    account_info: &'info AccountInfo<'info>,
+) -> Result<Account<'info, MarketInfo>> {
+    let market = Account::<MarketInfo>::try_from(account_info)?;
+
+    let expected_key = Pubkey::create_program_address(
+        &[
+            b"market",
+            blankon_state.as_ref(),
+            market.market_index.to_le_bytes().as_ref(),
+            &[market.bump],
+        ],
+        &crate::ID,
+    )
+    .map_err(|_| error!(DErrorCode::InvalidMarketAccount))?;
+
+    require_keys_eq!(
+        account_info.key(),
+        expected_key,
+        DErrorCode::InvalidMarketAccount
+    );
+
+    Ok(market)
+}
+
+/// A market loaded from `remaining_accounts` together with its price sources.
+pub struct LoadedMarket<'info> {
+    pub market: Account<'info, MarketInfo>,
+    pub pyth_price_account: Account<'info, PriceUpdateV2>,
+    pub fallback_price_account: Option<Account<'info, PushedPrice>>,
+}
+
+/// Splits `remaining_accounts` into validated `[market, pyth_price_account]` groups, each
+/// followed by the market's fallback price account if it has one.
+pub fn load_markets<'info>(
+    blankon_state: &Pubkey,
+    remaining_accounts: &'info [AccountInfo<'info>],
+) -> Result<Vec<LoadedMarket<'info>>> {
+    let mut markets: Vec<LoadedMarket<'info>> = Vec::new();
+    let mut accounts = remaining_accounts.iter();
+
+    while let Some(market_account) = accounts.next() {
+        let market = load_market(blankon_state, market_account)?;
+
+        let pyth_price_account = Account::<PriceUpdateV2>::try_from(
+            accounts.next().ok_or(DErrorCode::InvalidOracleAccount)?,
+        )?;
+        require!(
+            market.pyth_price_account == pyth_price_account.key(),
+            DErrorCode::InvalidOracleAccount
+        );
+
+        let fallback_price_account = if market.has_fallback_oracle() {
+            let fallback_price_account = Account::<PushedPrice>::try_from(
+                accounts.next().ok_or(DErrorCode::InvalidOracleAccount)?,
+            )?;
+            require!(
+                market.fallback_oracle == fallback_price_account.key(),
+                DErrorCode::InvalidOracleAccount
+            );
+            Some(fallback_price_account)
+        } else {
+            None
+        };
+
+        // Each market may only appear once so writes cannot overwrite each other
+        require!(
+            !markets
+                .iter()
+                .any(|loaded| loaded.market.market_index == market.market_index),
+            DErrorCode::InvalidMarketAccount
+        );
+
+        markets.push(LoadedMarket {
+            market,
+            pyth_price_account,
+            fallback_price_account,
+        });
+    }
+
+    Ok(markets)
+}
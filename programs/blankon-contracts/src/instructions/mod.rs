@@ -1,11 +1,25 @@
+mod collateral;
+mod fallback_oracle;
+mod fees;
 mod funding;
 mod initialize;
+mod liquidation;
+mod market;
+mod paper;
 mod positions;
+mod referral;
 mod status;
 mod user_account;
 
+pub use collateral::*;
+pub use fallback_oracle::*;
+pub use fees::*;
 pub use funding::*;
 pub use initialize::*;
+pub use liquidation::*;
+pub use market::*;
+pub use paper::*;
 pub use positions::*;
+pub use referral::*;
 pub use status::*;
 pub use user_account::*;
@@ -0,0 +1,81 @@
+use crate::errors::*;
+use crate::state::*;
+use anchor_lang::prelude::*;
+
+#[derive(Accounts)]
+pub struct UpdatePaperConfig<'info> {
+    #[account(
+        mut,
+        constraint = blankon_state.admin == admin.key() @ DErrorCode::UnauthorizedAccess,
+        constraint = blankon_state.mode == TradingMode::Paper @ DErrorCode::InvalidTradingMode
+    )]
+    pub blankon_state: Account<'info, BlankonState>,
+
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct FaucetTopUp<'info> {
+    #[account(
+        constraint = blankon_state.mode == TradingMode::Paper @ DErrorCode::InvalidTradingMode
+    )]
+    pub blankon_state: Account<'info, BlankonState>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"user-account",
+            blankon_state.key().as_ref(),
+            user.key().as_ref()
+        ],
+        bump,
+        constraint = user_account.owner == user.key() @ DErrorCode::UnauthorizedAccess
+    )]
+    pub user_account: Account<'info, UserAccount>,
+
+    pub user: Signer<'info>,
+}
+
+pub fn update_paper_config_handler(
+    ctx: Context<UpdatePaperConfig>,
+    paper_config: PaperConfig,
+) -> Result<()> {
+    paper_config.validate()?;
+
+    ctx.accounts.blankon_state.paper_config = paper_config;
+
+    msg!(
+        "Updated paper config: starting_balance={}, faucet_amount={}, faucet_cooldown={}",
+        paper_config.starting_balance,
+        paper_config.faucet_amount,
+        paper_config.faucet_cooldown
+    );
+
+    Ok(())
+}
+
+pub fn faucet_top_up_handler(ctx: Context<FaucetTopUp>) -> Result<()> {
+    let paper_config = ctx.accounts.blankon_state.paper_config;
+    let user_account = &mut ctx.accounts.user_account;
+    let current_time = Clock::get()?.unix_timestamp;
+
+    require!(paper_config.faucet_amount > 0, DErrorCode::InvalidAmount);
+    require!(
+        current_time - user_account.last_faucet_time >= paper_config.faucet_cooldown,
+        DErrorCode::FaucetCooldown
+    );
+
+    user_account.balance = user_account
+        .balance
+        .checked_add(paper_config.faucet_amount)
+        .ok_or(DErrorCode::MathOverflow)?;
+    user_account.last_faucet_time = current_time;
+
+    msg!(
+        "Faucet credited {} units, new balance {}",
+        paper_config.faucet_amount,
+        user_account.balance
+    );
+
+    Ok(())
+}
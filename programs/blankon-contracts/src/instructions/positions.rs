@@ -1,7 +1,8 @@
 use crate::constants::*;
 use crate::errors::*;
+use crate::instructions::accrue_funding;
 use crate::math::*;
-use crate::pyth::*;
+use crate::oracle::*;
 use crate::state::*;
 use anchor_lang::prelude::*;
 use pyth_solana_receiver_sdk::price_update::PriceUpdateV2;
@@ -13,7 +14,22 @@ pub struct OpenPosition<'info> {
 
     #[account(
         mut,
-        seeds = [b"user-account", user.key().as_ref()],
+        seeds = [
+            b"market",
+            blankon_state.key().as_ref(),
+            market.market_index.to_le_bytes().as_ref()
+        ],
+        bump = market.bump
+    )]
+    pub market: Account<'info, MarketInfo>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"user-account",
+            blankon_state.key().as_ref(),
+            user.key().as_ref()
+        ],
         bump,
         constraint = user_account.owner == user.key() @ DErrorCode::UnauthorizedAccess
     )]
@@ -22,96 +38,213 @@ pub struct OpenPosition<'info> {
     pub user: Signer<'info>,
 
     pub pyth_price_account: Account<'info, PriceUpdateV2>,
+
+    // Required when the market has a fallback oracle
+    #[account(address = market.fallback_oracle @ DErrorCode::InvalidOracleAccount)]
+    pub fallback_price_account: Option<Account<'info, PushedPrice>>,
+
+    #[account(
+        mut,
+        seeds = [b"insurance-fund", blankon_state.key().as_ref()],
+        bump = insurance_fund.bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+
+    // Required when the user was referred, receives the referrer's share of the fees
+    #[account(mut, address = user_account.referral_code @ DErrorCode::InvalidReferralCode)]
+    pub referral_code: Option<Account<'info, ReferralCode>>,
 }
 
 pub fn apply_funding_handler(ctx: &mut Context<OpenPosition>) -> Result<()> {
-    let blankon_state = &ctx.accounts.blankon_state;
+    accrue_funding(&mut ctx.accounts.market, Clock::get()?.unix_timestamp)?;
+    settle_funding(&ctx.accounts.market, &mut ctx.accounts.user_account)
+}
 
-    // Process each market
-    for (asset_idx, market) in blankon_state.markets.iter().enumerate() {
-        let position = ctx.accounts.user_account.positions[asset_idx];
+// Settle the funding accrued by the user's position in `market` since it was last settled
+pub fn settle_funding(market: &MarketInfo, user_account: &mut UserAccount) -> Result<()> {
+    let market_index = market.market_index;
+    let global_funding_index = market.global_funding_index;
 
-        // Skip if user has no position
-        if position.size == 0 {
-            continue;
-        }
+    // Skip if user has no position in this market
+    let position = match user_account.position(market_index) {
+        Some(position) if position.size != 0 => *position,
+        _ => return Ok(()),
+    };
 
-        // Calculate funding based on the difference between global and user's last funding index
-        let funding_index_delta = market.global_funding_index - position.last_funding_index as i128;
+    // Calculate funding based on the difference between global and user's last funding index
+    let funding_index_delta = global_funding_index - position.last_funding_index;
 
-        // Skip if no funding to apply
-        if funding_index_delta == 0 {
-            continue;
-        }
+    // Skip if no funding to apply
+    if funding_index_delta == 0 {
+        return Ok(());
+    }
 
-        // Calculate position notional value
-        let position_notional =
-            (position.size.abs() as u128 * position.entry_price as u128 / PRICE_DECIMALS) as u64;
-
-        // Calculate funding amount
-        // Funding amount = position notional * funding index delta
-        let funding_amount =
-            (position_notional as i128 * funding_index_delta) / FUNDING_INDEX_DECIMALS as i128;
-
-        // Apply funding:
-        // - Long positions pay positive funding rate (pay when funding_index_delta > 0)
-        // - Short positions pay negative funding rate (pay when funding_index_delta < 0)
-        let funding_to_pay = if position.size > 0 {
-            // Long position
-            funding_amount
-        } else {
-            // Short position
-            -funding_amount
-        };
-
-        // Update user balance based on funding
-        if funding_to_pay > 0 {
-            // User pays funding
-            let funding_payment = funding_to_pay as u64;
-
-            // Cap the payment to the user's balance to prevent underflow
-
-            {
-                let user_account = &mut ctx.accounts.user_account;
-
-                let payment = std::cmp::min(funding_payment, user_account.balance);
-                user_account.balance = user_account
-                    .balance
-                    .checked_sub(payment)
-                    .ok_or(DErrorCode::MathOverflow)?;
-
-                msg!(
-                    "User paid {} funding for {} position in market {}",
-                    payment,
-                    if position.size > 0 { "LONG" } else { "SHORT" },
-                    asset_idx
-                );
-            }
-        } else if funding_to_pay < 0 {
-            // User receives funding
-            let funding_receipt = (-funding_to_pay) as u64;
-
-            {
-                let user_account = &mut ctx.accounts.user_account;
-                user_account.balance = user_account
-                    .balance
-                    .checked_add(funding_receipt)
-                    .ok_or(DErrorCode::MathOverflow)?;
-            }
+    let funding_to_pay =
+        calculate_funding_owed(position.size, position.entry_price, funding_index_delta);
 
-            msg!(
-                "User received {} funding for {} position in market {}",
-                funding_receipt,
-                if position.size > 0 { "LONG" } else { "SHORT" },
-                asset_idx
-            );
-        }
+    // Update user balance based on funding
+    if funding_to_pay > 0 {
+        // User pays funding
+        let funding_payment = funding_to_pay as u64;
+
+        // Cap the payment to the user's balance to prevent underflow
+        let payment = std::cmp::min(funding_payment, user_account.balance);
+        user_account.balance = user_account
+            .balance
+            .checked_sub(payment)
+            .ok_or(DErrorCode::MathOverflow)?;
+
+        msg!(
+            "User paid {} funding for {} position in market {}",
+            payment,
+            if position.size > 0 { "LONG" } else { "SHORT" },
+            market_index
+        );
+    } else if funding_to_pay < 0 {
+        // User receives funding
+        let funding_receipt = (-funding_to_pay) as u64;
+
+        user_account.balance = user_account
+            .balance
+            .checked_add(funding_receipt)
+            .ok_or(DErrorCode::MathOverflow)?;
+
+        msg!(
+            "User received {} funding for {} position in market {}",
+            funding_receipt,
+            if position.size > 0 { "LONG" } else { "SHORT" },
+            market_index
+        );
+    }
+
+    // Update position's funding index to match global index
+    if let Some(position) = user_account.position_mut(market_index) {
+        position.last_funding_index = global_funding_index;
+    }
+
+    Ok(())
+}
+
+// Absorb a loss beyond a position's collateral: the insurance fund pays first, whatever it
+// cannot cover is socialized over the opposite side of the market and only recorded as bad
+// debt when there is nobody left to socialize it to
+pub fn absorb_shortfall(
+    blankon_state: &mut BlankonState,
+    insurance_fund: &mut InsuranceFund,
+    market: &mut MarketInfo,
+    bankrupt_size: i64,
+    shortfall: u64,
+) -> Result<()> {
+    let uncovered = insurance_fund.cover_shortfall(shortfall);
+    let bad_debt = market.socialize_loss(bankrupt_size, uncovered);
+
+    blankon_state.cumulative_socialized_loss = blankon_state
+        .cumulative_socialized_loss
+        .checked_add(uncovered - bad_debt)
+        .ok_or(DErrorCode::MathOverflow)?;
+    blankon_state.cumulative_bad_debt = blankon_state
+        .cumulative_bad_debt
+        .checked_add(bad_debt)
+        .ok_or(DErrorCode::MathOverflow)?;
+
+    msg!(
+        "Shortfall of {} in market {}: insurance fund covered {}, socialized {}, bad debt {}",
+        shortfall,
+        market.market_index,
+        shortfall - uncovered,
+        uncovered - bad_debt,
+        bad_debt
+    );
+
+    Ok(())
+}
+
+// Discount a trade's fee by the user's volume tier, and by the referee discount when the user
+// was referred
+pub fn discount_trade_fee(
+    blankon_state: &BlankonState,
+    user_account: &UserAccount,
+    trade_fee: u64,
+    current_time: i64,
+) -> u64 {
+    let trade_fee = apply_fee_discount(
+        trade_fee,
+        blankon_state.fee_discount(user_account.rolling_volume(current_time)),
+    );
 
-        // Update position's funding index to match global index
-        {
-            let position = &mut ctx.accounts.user_account.positions[asset_idx];
-            position.last_funding_index = market.global_funding_index;
+    if user_account.has_referrer() {
+        apply_fee_discount(trade_fee, blankon_state.referral_config.referee_discount)
+    } else {
+        trade_fee
+    }
+}
+
+// Split a charged fee between the user's referrer, the insurance fund and the fee pool,
+// returning the referrer's and the insurance fund's shares
+pub fn distribute_trade_fee(
+    blankon_state: &mut BlankonState,
+    insurance_fund: &mut InsuranceFund,
+    referral_code: Option<&mut ReferralCode>,
+    insurance_fee_share: u64,
+    trade_fee: u64,
+) -> Result<(u64, u64)> {
+    let referrer_reward = match referral_code {
+        Some(referral_code) => {
+            let reward = (trade_fee as u128 * blankon_state.referral_config.referrer_share as u128
+                / PERCENTAGE_DECIMALS as u128) as u64;
+
+            referral_code.claimable_rewards = referral_code
+                .claimable_rewards
+                .checked_add(reward)
+                .ok_or(DErrorCode::MathOverflow)?;
+            referral_code.total_rewards = referral_code
+                .total_rewards
+                .checked_add(reward)
+                .ok_or(DErrorCode::MathOverflow)?;
+
+            reward
         }
+        None => 0,
+    };
+
+    // The insurance fund takes its share of what the protocol keeps
+    let protocol_fee = trade_fee - referrer_reward;
+    let insurance_share =
+        (protocol_fee as u128 * insurance_fee_share as u128 / PERCENTAGE_DECIMALS as u128) as u64;
+
+    insurance_fund.balance = insurance_fund
+        .balance
+        .checked_add(insurance_share)
+        .ok_or(DErrorCode::MathOverflow)?;
+    blankon_state.fee_pool = blankon_state
+        .fee_pool
+        .checked_add(protocol_fee - insurance_share)
+        .ok_or(DErrorCode::MathOverflow)?;
+
+    Ok((referrer_reward, insurance_share))
+}
+
+// Reject trades executed after the trader's deadline, if they set one
+pub fn check_expiry(expiry_timestamp: Option<i64>, current_time: i64) -> Result<()> {
+    if let Some(expiry_timestamp) = expiry_timestamp {
+        require!(current_time <= expiry_timestamp, DErrorCode::OrderExpired);
+    }
+
+    Ok(())
+}
+
+// Reject a fill worse than the trader's bound: above it when buying, below it when selling
+pub fn check_acceptable_price(fill_price: u64, buying: bool, acceptable_price: u64) -> Result<()> {
+    if buying {
+        require!(
+            fill_price <= acceptable_price,
+            DErrorCode::PriceSlippageExceeded
+        );
+    } else {
+        require!(
+            fill_price >= acceptable_price,
+            DErrorCode::PriceSlippageExceeded
+        );
     }
 
     Ok(())
@@ -121,22 +254,27 @@ pub fn apply_funding_handler(ctx: &mut Context<OpenPosition>) -> Result<()> {
 
 pub fn open_handler(
     ctx: Context<OpenPosition>,
-    asset_type: u8,
     size: i64,
     leverage: u8,
+    acceptable_price: u64,
+    expiry_timestamp: Option<i64>,
 ) -> Result<()> {
     // Validate inputs
-    require!(asset_type < 3, DErrorCode::InvalidAssetType);
     require!(size != 0, DErrorCode::InvalidPositionSize);
+
+    // Get the market info
+    let market = &mut ctx.accounts.market;
+    let market_index = market.market_index;
+    let params = market.params;
+
     require!(
-        leverage > 0 && leverage <= MAX_LEVERAGE,
+        leverage > 0 && leverage <= params.max_leverage,
         DErrorCode::InvalidLeverage
     );
-
-    let blankon_state = &mut ctx.accounts.blankon_state;
-
-    // Get the market info
-    let market = &mut blankon_state.markets[asset_type as usize];
+    require!(
+        size.unsigned_abs() <= params.max_position_size,
+        DErrorCode::PositionTooLarge
+    );
 
     // Verify the correct Pyth account is provided
     require!(
@@ -144,6 +282,33 @@ pub fn open_handler(
         DErrorCode::InvalidOracleAccount
     );
 
+    // A referred user's trades must pay the referrer's share
+    require!(
+        ctx.accounts.referral_code.is_some() == ctx.accounts.user_account.has_referrer(),
+        DErrorCode::InvalidReferralCode
+    );
+
+    // Get the current price from Pyth, refusing to open against an uncertain price
+    let oracle_price = get_oracle_price(
+        market,
+        &*ctx.accounts.pyth_price_account,
+        ctx.accounts.fallback_price_account.as_deref(),
+    )?;
+    require!(
+        oracle_price.confidence_ratio() <= params.max_confidence_ratio,
+        DErrorCode::OracleConfidenceTooWide
+    );
+
+    // A price jumping outside the band is never traded against, it halts the market instead.
+    // The trade is skipped rather than failed so that the halt persists.
+    let current_time = Clock::get()?.unix_timestamp;
+    check_expiry(expiry_timestamp, current_time)?;
+    if !market.check_price_band(oracle_price.price, current_time)? {
+        return Ok(());
+    }
+    market.record_price(oracle_price.price, current_time);
+    let previous_skew = market.skew;
+
     // Update market skew
     if size > 0 {
         // Long position
@@ -155,61 +320,101 @@ pub fn open_handler(
         // Short position
         market.total_short_size = market
             .total_short_size
-            .checked_add((-size) as u64)
+            .checked_add(size.unsigned_abs())
             .ok_or(DErrorCode::MathOverflow)?;
     }
 
     // Recalculate market skew
     market.skew = market.total_long_size as i64 - market.total_short_size as i64;
+    market.sample_premium(oracle_price.price, current_time);
 
-    // Get the current price from Pyth
-    let base_price = get_pyth_price(&ctx.accounts.pyth_price_account, asset_type)?;
-
-    // Calculate the entry price based on market skew
-    let entry_price = calculate_price_from_skew(base_price, market.skew, SKEW_SCALE);
+    // The trade fills at the average premium over the skew it moved through
+    let entry_price =
+        calculate_fill_price(oracle_price.price, previous_skew, size, params.skew_scale);
+    check_acceptable_price(entry_price, size > 0, acceptable_price)?;
 
     // Calculate the required margin
-    let position_notional =
-        (size.abs() as u128 * leverage as u128 * entry_price as u128 / PRICE_DECIMALS) as u64;
-    let required_margin =
-        ((position_notional * INITIAL_MARGIN_REQUIREMENT) / PERCENTAGE_DECIMALS) / leverage as u64;
+    let required_margin = calculate_margin(
+        size,
+        leverage,
+        entry_price,
+        params.initial_margin_requirement,
+    )?;
+
+    let user_account = &mut ctx.accounts.user_account;
+
+    // Trades that reduce the skew pay the lower maker fee
+    let trade_fee = discount_trade_fee(
+        &ctx.accounts.blankon_state,
+        user_account,
+        calculate_trade_fee(
+            previous_skew,
+            size,
+            leverage,
+            entry_price,
+            params.maker_fee,
+            params.taker_fee,
+        )?,
+        current_time,
+    );
 
     require!(
-        ctx.accounts.user_account.balance >= required_margin,
+        user_account.balance
+            >= required_margin
+                .checked_add(trade_fee)
+                .ok_or(DErrorCode::MathOverflow)?,
         DErrorCode::InsufficientBalance
     );
 
-    // Check if user already has a position for this asset
-    {
-        let position = &mut ctx.accounts.user_account.positions[asset_type as usize];
-        require!(position.size == 0, DErrorCode::PositionAlreadyExists);
-    }
-
-    let user_account = &mut ctx.accounts.user_account;
+    // Check if user already has a position for this market
+    require!(
+        user_account.position(market_index).is_none(),
+        DErrorCode::PositionAlreadyExists
+    );
+    require!(
+        user_account.positions.len() < MAX_POSITIONS,
+        DErrorCode::TooManyPositions
+    );
 
     // Update user's balance
     user_account.balance = user_account
         .balance
         .checked_sub(required_margin)
+        .ok_or(DErrorCode::MathOverflow)?
+        .checked_sub(trade_fee)
         .ok_or(DErrorCode::MathOverflow)?;
 
-    {
-        let position = &mut ctx.accounts.user_account.positions[asset_type as usize];
-        // Update position
-        position.size = size;
-        position.entry_price = entry_price;
-        position.last_funding_index = 0; // Will be updated in funding calculations
-        position.leverage = leverage;
-    }
+    let (referrer_reward, insurance_share) = distribute_trade_fee(
+        &mut ctx.accounts.blankon_state,
+        &mut ctx.accounts.insurance_fund,
+        ctx.accounts.referral_code.as_deref_mut(),
+        params.insurance_fee_share,
+        trade_fee,
+    )?;
+
+    user_account.record_volume(
+        calculate_notional(size, leverage, entry_price)?,
+        current_time,
+    );
+    user_account.positions.push(Position::open(
+        market,
+        size,
+        entry_price,
+        leverage,
+        required_margin,
+    ));
 
     msg!(
-        "Opened {} position for asset {}: size={}, leverage={}x, margin={}, entry_price={}",
+        "Opened {} position for market {}: size={}, leverage={}x, margin={}, entry_price={}, fee={}, referrer_reward={}, insurance_share={}",
         if size > 0 { "LONG" } else { "SHORT" },
-        asset_type,
-        size.abs(),
+        market_index,
+        size.unsigned_abs(),
         leverage,
         required_margin,
-        entry_price
+        entry_price,
+        trade_fee,
+        referrer_reward,
+        insurance_share
     );
 
     Ok(())
@@ -217,14 +422,14 @@ pub fn open_handler(
 
 // ===== CLOSE POSITION =====
 
-pub fn close_handler(ctx: Context<OpenPosition>, asset_type: u8) -> Result<()> {
-    // Validate inputs
-    require!(asset_type < 3, DErrorCode::InvalidAssetType);
-
-    let blankon_state = &mut ctx.accounts.blankon_state;
-
+pub fn close_handler(
+    ctx: Context<OpenPosition>,
+    acceptable_price: u64,
+    expiry_timestamp: Option<i64>,
+) -> Result<()> {
     // Get the market info
-    let market = &mut blankon_state.markets[asset_type as usize];
+    let market = &mut ctx.accounts.market;
+    let market_index = market.market_index;
 
     // Verify the correct Pyth account is provided
     require!(
@@ -232,48 +437,75 @@ pub fn close_handler(ctx: Context<OpenPosition>, asset_type: u8) -> Result<()> {
         DErrorCode::InvalidOracleAccount
     );
 
-    // Get the position
-    let position = ctx.accounts.user_account.positions[asset_type as usize];
+    // A referred user's trades must pay the referrer's share
+    require!(
+        ctx.accounts.referral_code.is_some() == ctx.accounts.user_account.has_referrer(),
+        DErrorCode::InvalidReferralCode
+    );
 
-    // Check if position exists
+    // Get the position and check that it exists
+    let position = *ctx
+        .accounts
+        .user_account
+        .position(market_index)
+        .ok_or(DErrorCode::NoPositionExists)?;
     require!(position.size != 0, DErrorCode::NoPositionExists);
 
-    // Get the current price from Pyth
-    let base_price = get_pyth_price(&ctx.accounts.pyth_price_account, asset_type)?;
-
-    // Calculate the exit price based on market skew
-    let exit_price = calculate_price_from_skew(base_price, market.skew, SKEW_SCALE);
-
-    // Calculate PnL
-    let position_size = position.size.abs() as u128 * position.leverage as u128;
-    let entry_value = position_size * position.entry_price as u128 / PRICE_DECIMALS;
-    let exit_value = position_size * exit_price as u128 / PRICE_DECIMALS;
+    // Get the current price from Pyth. Closing is always allowed, but when the price is
+    // uncertain the position exits at the unfavourable edge of the confidence interval
+    let oracle_price = get_oracle_price(
+        market,
+        &*ctx.accounts.pyth_price_account,
+        ctx.accounts.fallback_price_account.as_deref(),
+    )?;
+
+    // Never close against a price outside the band, see open_handler
+    let current_time = Clock::get()?.unix_timestamp;
+    check_expiry(expiry_timestamp, current_time)?;
+    if !market.check_price_band(oracle_price.price, current_time)? {
+        return Ok(());
+    }
+    market.record_price(oracle_price.price, current_time);
 
-    let pnl = if position.size > 0 {
-        // Long position: profit if exit_value > entry_value
-        if exit_value > entry_value {
-            (exit_value - entry_value) as i64
+    let base_price = if oracle_price.confidence_ratio() > market.params.max_confidence_ratio {
+        if position.size > 0 {
+            oracle_price.price.saturating_sub(oracle_price.conf)
         } else {
-            -((entry_value - exit_value) as i64)
+            oracle_price.price.saturating_add(oracle_price.conf)
         }
     } else {
-        // Short position: profit if entry_value > exit_value
-        if entry_value > exit_value {
-            (entry_value - exit_value) as i64
-        } else {
-            -((exit_value - entry_value) as i64)
-        }
+        oracle_price.price
     };
 
-    // Calculate the margin that was locked
-    let position_notional =
-        (position.size.abs() as u128 * position.leverage as u128 * position.entry_price as u128
-            / PRICE_DECIMALS) as u64;
-    let locked_margin = position_notional * INITIAL_MARGIN_REQUIREMENT
-        / PERCENTAGE_DECIMALS
-        / position.leverage as u64;
+    // Closing fills at the average premium over the skew it unwinds
+    let exit_price = calculate_fill_price(
+        base_price,
+        market.skew,
+        -position.size,
+        market.params.skew_scale,
+    );
+
+    // Closing a short buys the position back, closing a long sells it
+    check_acceptable_price(exit_price, position.size < 0, acceptable_price)?;
+
+    // Calculate PnL, less any losses socialized onto this side since the position opened
+    let pnl = calculate_pnl(
+        position.size,
+        position.leverage,
+        position.entry_price,
+        exit_price,
+    )?;
+    let socialized_haircut = calculate_socialized_haircut(
+        market.socialized_loss_index(position.size) - position.last_socialized_loss_index,
+        position.size,
+        pnl,
+    );
+    let pnl = pnl - socialized_haircut as i64;
+
+    // The margin that was locked at open
+    let locked_margin = position.margin;
 
-    let user_account: &mut Account<'_, UserAccount> = &mut ctx.accounts.user_account;
+    let user_account = &mut ctx.accounts.user_account;
 
     // Update user's balance (return margin + PnL)
     if pnl >= 0 {
@@ -286,10 +518,24 @@ pub fn close_handler(ctx: Context<OpenPosition>, asset_type: u8) -> Result<()> {
         user_account.balance = new_balance;
     } else {
         // Ensure we don't underflow if loss exceeds margin
-        let loss = pnl.abs() as u64;
+        let loss = pnl.unsigned_abs();
         if loss >= locked_margin {
-            // Loss exceeds margin, user loses entire margin
-            // No need to subtract from balance as margin was already deducted when opening
+            // Loss exceeds margin, user loses entire margin and the excess comes out of
+            // the free balance. Anything beyond that is a shortfall.
+            let excess_loss = loss - locked_margin;
+            let from_balance = std::cmp::min(excess_loss, user_account.balance);
+            user_account.balance -= from_balance;
+
+            let shortfall = excess_loss - from_balance;
+            if shortfall > 0 {
+                absorb_shortfall(
+                    &mut ctx.accounts.blankon_state,
+                    &mut ctx.accounts.insurance_fund,
+                    market,
+                    position.size,
+                    shortfall,
+                )?;
+            }
         } else {
             // Return remaining margin after loss
             user_account.balance = user_account
@@ -299,6 +545,38 @@ pub fn close_handler(ctx: Context<OpenPosition>, asset_type: u8) -> Result<()> {
         }
     }
 
+    // Closing trades against the position, so it pays the maker fee on the part that reduces the
+    // skew. The fee comes out of whatever the close returned to the balance.
+    let trade_fee = std::cmp::min(
+        discount_trade_fee(
+            &ctx.accounts.blankon_state,
+            user_account,
+            calculate_trade_fee(
+                market.skew,
+                -position.size,
+                position.leverage,
+                exit_price,
+                market.params.maker_fee,
+                market.params.taker_fee,
+            )?,
+            current_time,
+        ),
+        user_account.balance,
+    );
+    user_account.balance -= trade_fee;
+    user_account.record_volume(
+        calculate_notional(position.size, position.leverage, exit_price)?,
+        current_time,
+    );
+
+    let (referrer_reward, insurance_share) = distribute_trade_fee(
+        &mut ctx.accounts.blankon_state,
+        &mut ctx.accounts.insurance_fund,
+        ctx.accounts.referral_code.as_deref_mut(),
+        market.params.insurance_fee_share,
+        trade_fee,
+    )?;
+
     // Update market skew
     if position.size > 0 {
         // Long position
@@ -310,28 +588,122 @@ pub fn close_handler(ctx: Context<OpenPosition>, asset_type: u8) -> Result<()> {
         // Short position
         market.total_short_size = market
             .total_short_size
-            .checked_sub((-position.size) as u64)
+            .checked_sub(position.size.unsigned_abs())
             .ok_or(DErrorCode::MathOverflow)?;
     }
 
     // Recalculate market skew
     market.skew = market.total_long_size as i64 - market.total_short_size as i64;
+    market.sample_premium(oracle_price.price, current_time);
 
-    {
-        let position = &mut ctx.accounts.user_account.positions[asset_type as usize];
-        // Clear the position
-        position.size = 0;
-        position.entry_price = 0;
-        position.last_funding_index = 0;
-        position.leverage = 0;
-    }
+    // Clear the position
+    user_account.remove_position(market_index);
 
     msg!(
-        "Closed position for asset {}: PnL={}, exit_price={}",
-        asset_type,
+        "Closed position for market {}: PnL={}, socialized_haircut={}, exit_price={}, fee={}, referrer_reward={}, insurance_share={}",
+        market_index,
         pnl,
-        exit_price
+        socialized_haircut,
+        exit_price,
+        trade_fee,
+        referrer_reward,
+        insurance_share
     );
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::accrue_funding;
+    use crate::state::tests::{market, user_account};
+
+    const PERIOD: i64 = 6 * 3_600;
+    const DAY: i64 = 86_400;
+
+    // Accrue funding every six hours from `from` to `to`, like a crank would
+    fn crank(market: &mut MarketInfo, from: i64, to: i64) {
+        for time in (from + PERIOD..=to).step_by(PERIOD as usize) {
+            accrue_funding(market, time).unwrap();
+        }
+    }
+
+    #[test]
+    fn new_position_starts_at_current_funding_index() {
+        let mut market = market();
+        // Longs outweigh shorts by 10% of the skew scale: 0.1% per day
+        market.skew = 100_000_000;
+
+        // 30 days of funding before the position exists
+        crank(&mut market, 0, 30 * DAY);
+        assert!(market.global_funding_index > 0);
+
+        let position = Position::open(&market, 10_000_000, 100_000_000, 5, 200_000_000);
+        assert_eq!(position.last_funding_index, market.global_funding_index);
+
+        // Settling straight away charges nothing
+        let mut user_account = user_account(1_000_000_000);
+        user_account.positions.push(position);
+        settle_funding(&market, &mut user_account).unwrap();
+        assert_eq!(user_account.balance, 1_000_000_000);
+    }
+
+    #[test]
+    fn position_pays_only_funding_accrued_after_open() {
+        let mut market = market();
+        market.skew = 100_000_000;
+
+        crank(&mut market, 0, 30 * DAY);
+        let index_at_open = market.global_funding_index;
+
+        // Long 10 at $100: $1,000 notional
+        let mut user_account = user_account(1_000_000_000);
+        user_account.positions.push(Position::open(
+            &market,
+            10_000_000,
+            100_000_000,
+            5,
+            200_000_000,
+        ));
+
+        // One more day of funding at 0.1% per day costs the long $1
+        crank(&mut market, 30 * DAY, 31 * DAY);
+        settle_funding(&market, &mut user_account).unwrap();
+
+        assert_eq!(user_account.balance, 1_000_000_000 - 1_000_000);
+        assert_eq!(
+            user_account.positions[0].last_funding_index,
+            market.global_funding_index
+        );
+
+        // The history before the open would have cost thirty times as much
+        assert_eq!(
+            market.global_funding_index - index_at_open,
+            index_at_open / 30
+        );
+    }
+
+    #[test]
+    fn short_receives_only_funding_accrued_after_open() {
+        let mut market = market();
+        market.skew = 100_000_000;
+
+        crank(&mut market, 0, 100 * DAY);
+
+        let mut user_account = user_account(1_000_000_000);
+        user_account.positions.push(Position::open(
+            &market,
+            -10_000_000,
+            100_000_000,
+            5,
+            200_000_000,
+        ));
+
+        crank(&mut market, 100 * DAY, 102 * DAY);
+        settle_funding(&market, &mut user_account).unwrap();
+
+        // Two days at 0.1% per day on $1,000, not the 102 days since the market opened
+        assert_eq!(user_account.balance, 1_000_000_000 + 2_000_000);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::constants::*;
+use crate::errors::*;
+use crate::state::*;
+use anchor_lang::prelude::*;
+
+#[derive(Accounts)]
+#[instruction(code: [u8; REFERRAL_CODE_LEN])]
+pub struct RegisterReferralCode<'info> {
+    pub blankon_state: Account<'info, BlankonState>,
+
+    #[account(
+        init,
+        payer = referrer,
+        space = 8 + ReferralCode::LEN,
+        seeds = [b"referral-code", blankon_state.key().as_ref(), code.as_ref()],
+        bump
+    )]
+    pub referral_code: Account<'info, ReferralCode>,
+
+    #[account(mut)]
+    pub referrer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimReferralRewards<'info> {
+    pub blankon_state: Account<'info, BlankonState>,
+
+    #[account(
+        mut,
+        seeds = [b"referral-code", blankon_state.key().as_ref(), referral_code.code.as_ref()],
+        bump = referral_code.bump,
+        constraint = referral_code.referrer == referrer.key() @ DErrorCode::UnauthorizedAccess
+    )]
+    pub referral_code: Account<'info, ReferralCode>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"user-account",
+            blankon_state.key().as_ref(),
+            referrer.key().as_ref()
+        ],
+        bump,
+        constraint = referrer_account.owner == referrer.key() @ DErrorCode::UnauthorizedAccess
+    )]
+    pub referrer_account: Account<'info, UserAccount>,
+
+    pub referrer: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateReferralConfig<'info> {
+    #[account(
+        mut,
+        constraint = blankon_state.admin == admin.key() @ DErrorCode::UnauthorizedAccess
+    )]
+    pub blankon_state: Account<'info, BlankonState>,
+
+    pub admin: Signer<'info>,
+}
+
+pub fn register_referral_code_handler(
+    ctx: Context<RegisterReferralCode>,
+    code: [u8; REFERRAL_CODE_LEN],
+) -> Result<()> {
+    require!(
+        code != [0; REFERRAL_CODE_LEN],
+        DErrorCode::InvalidReferralCode
+    );
+
+    ctx.accounts.referral_code.set_inner(ReferralCode {
+        code,
+        referrer: ctx.accounts.referrer.key(),
+        referred_users: 0,
+        claimable_rewards: 0,
+        total_rewards: 0,
+        bump: ctx.bumps.referral_code,
+    });
+
+    msg!(
+        "Referral code {} registered by {}",
+        ctx.accounts.referral_code.key(),
+        ctx.accounts.referrer.key()
+    );
+
+    Ok(())
+}
+
+// Move the referrer's accumulated fee share into their trading balance
+pub fn claim_referral_rewards_handler(ctx: Context<ClaimReferralRewards>) -> Result<()> {
+    let referral_code = &mut ctx.accounts.referral_code;
+    let rewards = referral_code.claimable_rewards;
+    require!(rewards > 0, DErrorCode::InvalidAmount);
+
+    let referrer_account = &mut ctx.accounts.referrer_account;
+    referrer_account.balance = referrer_account
+        .balance
+        .checked_add(rewards)
+        .ok_or(DErrorCode::MathOverflow)?;
+    referral_code.claimable_rewards = 0;
+
+    msg!(
+        "Claimed {} referral rewards, new balance {}",
+        rewards,
+        referrer_account.balance
+    );
+
+    Ok(())
+}
+
+pub fn update_referral_config_handler(
+    ctx: Context<UpdateReferralConfig>,
+    referral_config: ReferralConfig,
+) -> Result<()> {
+    referral_config.validate()?;
+
+    ctx.accounts.blankon_state.referral_config = referral_config;
+
+    msg!(
+        "Updated referral config: referrer_share={}, referee_discount={}",
+        referral_config.referrer_share,
+        referral_config.referee_discount
+    );
+
+    Ok(())
+}
@@ -1,20 +1,23 @@
-use crate::constants::{
-    INITIAL_MARGIN_REQUIREMENT, MAX_FUNDING_RATE, MAX_LEVERAGE, PERCENTAGE_DECIMALS,
-    PRICE_DECIMALS, SKEW_SCALE,
+use crate::constants::*;
+use crate::instructions::{calculate_funding_step, load_markets, LoadedMarket};
+use crate::math::{
+    calculate_funding_owed, calculate_margin, calculate_pnl, calculate_socialized_haircut,
 };
-use crate::math::calculate_funding_rate;
-use crate::pyth::get_pyth_price;
+use crate::oracle::*;
 use crate::state::*;
 use crate::{errors::*, math::calculate_price_from_skew};
 use anchor_lang::prelude::*;
-use pyth_solana_receiver_sdk::price_update::PriceUpdateV2;
 
 #[derive(Accounts)]
 pub struct UserStatus<'info> {
     pub blankon_state: Account<'info, BlankonState>,
 
     #[account(
-        seeds = [b"user-account", user.key().as_ref()],
+        seeds = [
+            b"user-account",
+            blankon_state.key().as_ref(),
+            user.key().as_ref()
+        ],
         bump,
         constraint = user_account.owner == user.key() @ DErrorCode::UnauthorizedAccess
     )]
@@ -22,26 +25,23 @@ pub struct UserStatus<'info> {
 
     pub user: Signer<'info>,
 
-    pub pyth_price_account_gold: Account<'info, PriceUpdateV2>,
-    pub pyth_price_account_sol: Account<'info, PriceUpdateV2>,
-    pub pyth_price_account_btc: Account<'info, PriceUpdateV2>,
-
     pub clock: Sysvar<'info, Clock>,
+    // remaining_accounts: [market, pyth_price_account] pair for each open position,
+    // followed by the market's fallback_price_account if it has one
 }
 
 #[derive(Accounts)]
 pub struct MarketStatus<'info> {
     pub blankon_state: Account<'info, BlankonState>,
 
-    pub pyth_price_account_gold: Account<'info, PriceUpdateV2>,
-    pub pyth_price_account_sol: Account<'info, PriceUpdateV2>,
-    pub pyth_price_account_btc: Account<'info, PriceUpdateV2>,
-
     pub clock: Sysvar<'info, Clock>,
+    // remaining_accounts: [market, pyth_price_account] pair for each requested market,
+    // followed by the market's fallback_price_account if it has one
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize)]
 pub struct PositionStatus {
+    pub market_index: u16,
     pub size: i64,
     pub entry_price: u64,
     pub current_price_oracle: u64,
@@ -51,65 +51,80 @@ pub struct PositionStatus {
     pub maintenance_margin: u64,
     pub claimable_value: i64,
     pub funding_index: i128,
-    pub funding_rate: i64,
+    pub funding_rate: i128, // Daily, scaled by FUNDING_INDEX_DECIMALS
     pub last_funding_time: i64,
     pub leverage: u8,
+    pub oracle_publish_time: i64,
+    pub oracle_stale: bool, // Trades against this market would be rejected until the oracle updates
+    pub oracle_source: OracleSource, // Source the reported prices come from
+    pub accrued_funding: i64, // Unsettled funding owed by (positive) or to (negative) the position
 }
 
 fn get_position_status(
-    blankon_state: &Account<'_, BlankonState>,
-    user_account: &Account<'_, UserAccount>,
-    pyth_price_account: &Account<'_, PriceUpdateV2>,
-    asset_type: u8,
+    loaded: &LoadedMarket,
+    position: &Position,
+    current_time: i64,
 ) -> Result<PositionStatus> {
-    let position = user_account.positions[asset_type as usize];
-    let market = blankon_state.markets[asset_type as usize];
-
-    let oracle_price = get_pyth_price(pyth_price_account, asset_type)?;
-    let amm_price = calculate_price_from_skew(oracle_price, market.skew, SKEW_SCALE);
-
-    let position_size = position.size.abs() as u128 * position.leverage as u128;
-    let entry_value = position_size * position.entry_price as u128 / PRICE_DECIMALS;
-    let current_value = position_size * amm_price as u128 / PRICE_DECIMALS;
+    let market = &loaded.market;
+    let reading = read_oracle_price(
+        market,
+        &*loaded.pyth_price_account,
+        loaded.fallback_price_account.as_deref(),
+        current_time,
+    )?;
+    let oracle = &reading.price;
+    let oracle_price = oracle.price;
+    let amm_price = calculate_price_from_skew(oracle_price, market.skew, market.params.skew_scale);
 
     let exists = position.size != 0 && position.leverage != 0;
 
-    let initial_margin = if exists {
-        entry_value as u64 * INITIAL_MARGIN_REQUIREMENT
-            / PERCENTAGE_DECIMALS
-            / position.leverage as u64
+    let initial_margin = position.margin;
+    let maintenance_margin = if exists {
+        calculate_margin(
+            position.size,
+            position.leverage,
+            amm_price,
+            market.params.maintenance_margin_requirement,
+        )?
     } else {
         0
     };
-    let maintenance_margin = current_value as u64 * INITIAL_MARGIN_REQUIREMENT
-        / PERCENTAGE_DECIMALS
-        / MAX_LEVERAGE as u64;
-
-    let pnl = if position.size > 0 {
-        // Long position: profit if exit_value > entry_value
-        if current_value > entry_value {
-            (current_value - entry_value) as i64
-        } else {
-            -((entry_value - current_value) as i64)
-        }
+
+    let pnl = if exists {
+        calculate_pnl(
+            position.size,
+            position.leverage,
+            position.entry_price,
+            amm_price,
+        )?
     } else {
-        // Short position: profit if entry_value > exit_value
-        if entry_value > current_value {
-            (entry_value - current_value) as i64
-        } else {
-            -((current_value - entry_value) as i64)
-        }
+        0
     };
 
+    // Winners only keep what is left after losses socialized onto their side
+    let pnl = pnl
+        - calculate_socialized_haircut(
+            market.socialized_loss_index(position.size) - position.last_socialized_loss_index,
+            position.size,
+            pnl,
+        ) as i64;
+
     let claimable_value = if pnl + initial_margin as i64 > 0 {
         pnl
     } else {
         0
     };
 
-    let funding_rate = calculate_funding_rate(market.skew, SKEW_SCALE, MAX_FUNDING_RATE);
+    // Funding the position would settle right now, including what the market has not accrued yet
+    let (funding_rate, pending_index_increment) = calculate_funding_step(market, current_time);
+    let accrued_funding = calculate_funding_owed(
+        position.size,
+        position.entry_price,
+        market.global_funding_index + pending_index_increment - position.last_funding_index,
+    );
 
     Ok(PositionStatus {
+        market_index: market.market_index,
         size: position.size * position.leverage as i64,
         entry_price: position.entry_price,
         current_price_oracle: oracle_price,
@@ -122,89 +137,145 @@ fn get_position_status(
         funding_rate,
         last_funding_time: market.last_funding_time,
         leverage: position.leverage,
+        oracle_publish_time: oracle.publish_time,
+        oracle_stale: oracle.is_stale(current_time, market.params.max_price_age_secs),
+        oracle_source: reading.source,
+        accrued_funding: accrued_funding as i64,
     })
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize)]
 pub struct MarketSnapshot {
+    pub market_index: u16,
     pub current_price_oracle: u64,
     pub current_price_amm: u64,
     pub funding_index: i128,
-    pub funding_rate: i64,
+    pub funding_rate: i128, // Daily, scaled by FUNDING_INDEX_DECIMALS
     pub last_funding_time: i64,
+    pub oracle_publish_time: i64,
+    pub oracle_stale: bool,
+    pub oracle_confidence: u64, // Normalized to 6 decimals like the price
+    pub oracle_confidence_ratio: u64, // Confidence / price, 4 decimals
+    pub oracle_confidence_too_wide: bool, // Opens against this market would be rejected
+    pub oracle_source: OracleSource, // Source the reported prices come from
+    pub oracle_deviation: Option<u64>, // Primary vs fallback price difference, 4 decimals
+    pub halted: bool,           // Trading is paused by the circuit breaker
+    pub last_accepted_price: u64, // Reference price of the circuit breaker band
+    pub twap_price: Option<u64>, // Over the market's TWAP window, None without history
 }
 
-fn get_market_status(
-    blankon_state: &Account<'_, BlankonState>,
-    pyth_price_account: &Account<'_, PriceUpdateV2>,
-    asset_type: u8,
-) -> Result<MarketSnapshot> {
-    let market = blankon_state.markets[asset_type as usize];
-
-    let oracle_price = get_pyth_price(pyth_price_account, asset_type)?;
-    let amm_price = calculate_price_from_skew(oracle_price, market.skew, SKEW_SCALE);
+fn get_market_status(loaded: &LoadedMarket, current_time: i64) -> Result<MarketSnapshot> {
+    let market = &loaded.market;
+    let reading = read_oracle_price(
+        market,
+        &*loaded.pyth_price_account,
+        loaded.fallback_price_account.as_deref(),
+        current_time,
+    )?;
+    let oracle = &reading.price;
+    let oracle_price = oracle.price;
+    let amm_price = calculate_price_from_skew(oracle_price, market.skew, market.params.skew_scale);
 
-    let funding_rate = calculate_funding_rate(market.skew, SKEW_SCALE, MAX_FUNDING_RATE);
+    let (funding_rate, _) = calculate_funding_step(market, current_time);
 
     Ok(MarketSnapshot {
+        market_index: market.market_index,
         current_price_oracle: oracle_price,
         current_price_amm: amm_price,
         funding_index: market.global_funding_index,
         funding_rate,
         last_funding_time: market.last_funding_time,
+        oracle_publish_time: oracle.publish_time,
+        oracle_stale: oracle.is_stale(current_time, market.params.max_price_age_secs),
+        oracle_confidence: oracle.conf,
+        oracle_confidence_ratio: oracle.confidence_ratio(),
+        oracle_confidence_too_wide: oracle.confidence_ratio() > market.params.max_confidence_ratio,
+        oracle_source: reading.source,
+        oracle_deviation: reading.deviation,
+        halted: market.is_halted(current_time),
+        last_accepted_price: market.last_accepted_price,
+        twap_price: market.twap(current_time),
     })
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize)]
 pub struct UserSnapshot {
     pub balance: u64,
-    pub position_status: [PositionStatus; 3],
+    pub rolling_volume: u64, // Notional traded over the last 30 days
+    pub fee_discount: u64,   // Volume tier discount off trading fees, 4 decimals
+    pub position_status: Vec<PositionStatus>,
 }
 
-pub fn user_status_handler(ctx: Context<UserStatus>) -> Result<UserSnapshot> {
-    let blankon_state = &ctx.accounts.blankon_state;
+pub fn user_status_handler<'info>(
+    ctx: Context<'_, '_, 'info, 'info, UserStatus<'info>>,
+) -> Result<UserSnapshot> {
     let user_account = &ctx.accounts.user_account;
+    let markets = load_markets(&ctx.accounts.blankon_state.key(), ctx.remaining_accounts)?;
 
-    let snapshot = UserSnapshot {
-        balance: ctx.accounts.user_account.balance,
-        position_status: [
-            get_position_status(
-                blankon_state,
-                user_account,
-                &ctx.accounts.pyth_price_account_gold,
-                GOLD,
-            )?,
-            get_position_status(
-                blankon_state,
-                user_account,
-                &ctx.accounts.pyth_price_account_sol,
-                SOL,
-            )?,
-            get_position_status(
-                blankon_state,
-                user_account,
-                &ctx.accounts.pyth_price_account_btc,
-                BTC,
-            )?,
-        ],
-    };
+    let mut position_status = Vec::with_capacity(user_account.positions.len());
+    for position in user_account.positions.iter() {
+        let loaded = markets
+            .iter()
+            .find(|loaded| loaded.market.market_index == position.market_index)
+            .ok_or(DErrorCode::InvalidMarketAccount)?;
+
+        position_status.push(get_position_status(
+            loaded,
+            position,
+            ctx.accounts.clock.unix_timestamp,
+        )?);
+    }
+
+    let rolling_volume = user_account.rolling_volume(ctx.accounts.clock.unix_timestamp);
 
-    Ok(snapshot)
+    Ok(UserSnapshot {
+        balance: user_account.balance,
+        rolling_volume,
+        fee_discount: ctx.accounts.blankon_state.fee_discount(rolling_volume),
+        position_status,
+    })
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize)]
 pub struct MarketSnapshots {
-    pub market_snapshots: [MarketSnapshot; 3],
+    pub market_snapshots: Vec<MarketSnapshot>,
 }
 
-pub fn market_status_handler(ctx: Context<MarketStatus>) -> Result<MarketSnapshots> {
-    let blankon_state = &ctx.accounts.blankon_state;
+pub fn market_status_handler<'info>(
+    ctx: Context<'_, '_, 'info, 'info, MarketStatus<'info>>,
+) -> Result<MarketSnapshots> {
+    let markets = load_markets(&ctx.accounts.blankon_state.key(), ctx.remaining_accounts)?;
 
     Ok(MarketSnapshots {
-        market_snapshots: [
-            get_market_status(blankon_state, &ctx.accounts.pyth_price_account_gold, GOLD)?,
-            get_market_status(blankon_state, &ctx.accounts.pyth_price_account_sol, SOL)?,
-            get_market_status(blankon_state, &ctx.accounts.pyth_price_account_btc, BTC)?,
-        ],
+        market_snapshots: markets
+            .iter()
+            .map(|loaded| get_market_status(loaded, ctx.accounts.clock.unix_timestamp))
+            .collect::<Result<Vec<_>>>()?,
+    })
+}
+
+#[derive(Accounts)]
+pub struct FundingHistoryStatus<'info> {
+    pub funding_history: Box<Account<'info, FundingHistory>>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize)]
+pub struct FundingHistorySnapshot {
+    pub market: Pubkey,
+    pub total_records: u64,
+    pub records: Vec<FundingRecord>, // Oldest first, at most FUNDING_HISTORY_PAGE_SIZE
+}
+
+// Page through the kept funding records, `start` counting from the oldest one
+pub fn funding_history_handler(
+    ctx: Context<FundingHistoryStatus>,
+    start: u16,
+) -> Result<FundingHistorySnapshot> {
+    let funding_history = &ctx.accounts.funding_history;
+
+    Ok(FundingHistorySnapshot {
+        market: funding_history.market,
+        total_records: funding_history.total_records,
+        records: funding_history.records_from(start as usize, FUNDING_HISTORY_PAGE_SIZE),
     })
 }
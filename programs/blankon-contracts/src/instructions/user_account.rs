@@ -1,14 +1,21 @@
 use crate::constants::*;
+use crate::errors::*;
 use crate::state::*;
 use anchor_lang::prelude::*;
 
 #[derive(Accounts)]
 pub struct CreateUserAccount<'info> {
+    pub blankon_state: Account<'info, BlankonState>,
+
     #[account(
         init,
         payer = user,
         space = 8 + UserAccount::LEN,
-        seeds = [b"user-account", user.key().as_ref()],
+        seeds = [
+            b"user-account",
+            blankon_state.key().as_ref(),
+            user.key().as_ref()
+        ],
         bump
     )]
     pub user_account: Account<'info, UserAccount>,
@@ -16,6 +23,15 @@ pub struct CreateUserAccount<'info> {
     #[account(mut)]
     pub user: Signer<'info>,
 
+    // Links the account to its referrer when provided
+    #[account(
+        mut,
+        seeds = [b"referral-code", blankon_state.key().as_ref(), referral_code.code.as_ref()],
+        bump = referral_code.bump,
+        constraint = referral_code.referrer != user.key() @ DErrorCode::InvalidReferralCode
+    )]
+    pub referral_code: Option<Account<'info, ReferralCode>>,
+
     pub system_program: Program<'info, System>,
 }
 
@@ -25,22 +41,41 @@ pub fn create_handler(ctx: Context<CreateUserAccount>) -> Result<()> {
     // Set the owner
     user_account.owner = ctx.accounts.user.key();
 
-    // Initialize with $10,000 balance (in lamports, assuming 6 decimal places)
-    user_account.balance = INITIAL_BALANCE; // 10_000_000_000 (10,000 with 6 decimals)
+    // Paper accounts start with the configured synthetic balance (6 decimal places), while
+    // collateralized accounts only get balance from deposits
+    let blankon_state = &ctx.accounts.blankon_state;
+    user_account.balance = match blankon_state.mode {
+        TradingMode::Paper => blankon_state.paper_config.starting_balance,
+        TradingMode::Collateralized => 0,
+    };
+
+    // Positions are added as markets are traded
+    user_account.positions = Vec::new();
+
+    user_account.last_faucet_time = 0;
+
+    // Volume starts counting from the first trade
+    user_account.volume_buckets = [0; VOLUME_WINDOW_DAYS];
+    user_account.volume_last_day = 0;
+
+    // The referrer is fixed at creation, trades then pass the same code to share their fees
+    user_account.referral_code = match ctx.accounts.referral_code.as_mut() {
+        Some(referral_code) => {
+            referral_code.referred_users = referral_code
+                .referred_users
+                .checked_add(1)
+                .ok_or(DErrorCode::MathOverflow)?;
+
+            msg!("User account referred by {}", referral_code.referrer);
 
-    // Initialize empty positions for all three markets
-    for i in 0..3 {
-        user_account.positions[i] = Position {
-            size: 0,
-            entry_price: 0,
-            last_funding_index: 0,
-            leverage: 0,
-        };
-    }
+            referral_code.key()
+        }
+        None => Pubkey::default(),
+    };
 
     msg!(
         "User account created with initial balance of {} units",
-        INITIAL_BALANCE
+        user_account.balance
     );
 
     Ok(())
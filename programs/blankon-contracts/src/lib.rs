@@ -2,11 +2,15 @@ pub mod constants;
 pub mod errors;
 pub mod instructions;
 pub mod math;
+pub mod oracle;
+pub mod price;
 pub mod pyth;
 pub mod state;
 
 pub use crate::instructions::*;
 
+use crate::constants::{FEE_TIER_COUNT, REFERRAL_CODE_LEN};
+use crate::state::*;
 use anchor_lang::prelude::*;
 use solana_security_txt::security_txt;
 
@@ -37,32 +41,120 @@ pub mod blankon_contracts {
 
     pub fn initialize(
         ctx: Context<Initialize>,
-        gold_pyth_account: Pubkey,
-        sol_pyth_account: Pubkey,
-        btc_pyth_account: Pubkey,
+        insurance_fund_share: u64,
+        mode: TradingMode,
+        paper_config: PaperConfig,
     ) -> Result<()> {
-        initialize_handler(ctx, gold_pyth_account, sol_pyth_account, btc_pyth_account)
+        initialize_handler(ctx, insurance_fund_share, mode, paper_config)
+    }
+
+    pub fn update_paper_config(
+        ctx: Context<UpdatePaperConfig>,
+        paper_config: PaperConfig,
+    ) -> Result<()> {
+        update_paper_config_handler(ctx, paper_config)
+    }
+
+    pub fn add_market(
+        ctx: Context<AddMarket>,
+        pyth_price_account: Pubkey,
+        pyth_feed_id: [u8; 32],
+        params: MarketParams,
+    ) -> Result<()> {
+        add_market_handler(ctx, pyth_price_account, pyth_feed_id, params)
+    }
+
+    pub fn update_market_params(
+        ctx: Context<UpdateMarketParams>,
+        params: MarketParams,
+    ) -> Result<()> {
+        update_market_params_handler(ctx, params)
+    }
+
+    pub fn resume_market(ctx: Context<ResumeMarket>) -> Result<()> {
+        resume_market_handler(ctx)
+    }
+
+    pub fn add_fallback_oracle(ctx: Context<AddFallbackOracle>, authority: Pubkey) -> Result<()> {
+        add_fallback_oracle_handler(ctx, authority)
+    }
+
+    pub fn push_price(ctx: Context<PushPrice>, price: u64, conf: u64) -> Result<()> {
+        push_price_handler(ctx, price, conf)
     }
 
     pub fn create_user_account(ctx: Context<CreateUserAccount>) -> Result<()> {
         create_handler(ctx)
     }
 
-    pub fn open_position<'info>(
-        mut ctx: Context<'_, '_, '_, 'info, OpenPosition<'info>>,
-        asset_type: u8,
+    pub fn register_referral_code(
+        ctx: Context<RegisterReferralCode>,
+        code: [u8; REFERRAL_CODE_LEN],
+    ) -> Result<()> {
+        register_referral_code_handler(ctx, code)
+    }
+
+    pub fn claim_referral_rewards(ctx: Context<ClaimReferralRewards>) -> Result<()> {
+        claim_referral_rewards_handler(ctx)
+    }
+
+    pub fn update_referral_config(
+        ctx: Context<UpdateReferralConfig>,
+        referral_config: ReferralConfig,
+    ) -> Result<()> {
+        update_referral_config_handler(ctx, referral_config)
+    }
+
+    pub fn faucet_top_up(ctx: Context<FaucetTopUp>) -> Result<()> {
+        faucet_top_up_handler(ctx)
+    }
+
+    pub fn initialize_vault(ctx: Context<InitializeVault>) -> Result<()> {
+        initialize_vault_handler(ctx)
+    }
+
+    pub fn deposit(ctx: Context<TransferCollateral>, amount: u64) -> Result<()> {
+        deposit_handler(ctx, amount)
+    }
+
+    pub fn withdraw<'info>(
+        ctx: Context<'_, '_, 'info, 'info, TransferCollateral<'info>>,
+        amount: u64,
+    ) -> Result<()> {
+        withdraw_handler(ctx, amount)
+    }
+
+    pub fn update_fee_tiers(
+        ctx: Context<UpdateFeeTiers>,
+        fee_tiers: [FeeTier; FEE_TIER_COUNT],
+    ) -> Result<()> {
+        update_fee_tiers_handler(ctx, fee_tiers)
+    }
+
+    pub fn collect_fees(ctx: Context<CollectFees>) -> Result<()> {
+        collect_fees_handler(ctx)
+    }
+
+    pub fn open_position(
+        mut ctx: Context<OpenPosition>,
         size: i64,
         leverage: u8,
+        acceptable_price: u64,
+        expiry_timestamp: Option<i64>,
     ) -> Result<()> {
         // Apply any pending funding before opening a new position
         apply_funding_handler(&mut ctx)?;
-        open_handler(ctx, asset_type, size, leverage)
+        open_handler(ctx, size, leverage, acceptable_price, expiry_timestamp)
     }
 
-    pub fn close_position(mut ctx: Context<OpenPosition>, asset_type: u8) -> Result<()> {
+    pub fn close_position(
+        mut ctx: Context<OpenPosition>,
+        acceptable_price: u64,
+        expiry_timestamp: Option<i64>,
+    ) -> Result<()> {
         // Apply any pending funding before closing the position
         apply_funding_handler(&mut ctx)?;
-        close_handler(ctx, asset_type)
+        close_handler(ctx, acceptable_price, expiry_timestamp)
     }
 
     pub fn calculate_funding(ctx: Context<CalculateFunding>) -> Result<()> {
@@ -73,11 +165,39 @@ pub mod blankon_contracts {
         apply_funding_handler(&mut ctx)
     }
 
-    pub fn get_user_status(ctx: Context<UserStatus>) -> Result<UserSnapshot> {
+    pub fn settle_market_funding(ctx: Context<SettleMarketFunding>) -> Result<()> {
+        settle_market_funding_handler(ctx)
+    }
+
+    pub fn settle_all_funding<'info>(
+        ctx: Context<'_, '_, 'info, 'info, SettleAllFunding<'info>>,
+    ) -> Result<()> {
+        settle_all_funding_handler(ctx)
+    }
+
+    pub fn liquidate<'info>(
+        ctx: Context<'_, '_, 'info, 'info, Liquidate<'info>>,
+        market_index: u16,
+    ) -> Result<()> {
+        liquidate_handler(ctx, market_index)
+    }
+
+    pub fn get_user_status<'info>(
+        ctx: Context<'_, '_, 'info, 'info, UserStatus<'info>>,
+    ) -> Result<UserSnapshot> {
         user_status_handler(ctx)
     }
 
-    pub fn get_market_status(ctx: Context<MarketStatus>) -> Result<MarketSnapshots> {
+    pub fn get_market_status<'info>(
+        ctx: Context<'_, '_, 'info, 'info, MarketStatus<'info>>,
+    ) -> Result<MarketSnapshots> {
         market_status_handler(ctx)
     }
+
+    pub fn get_funding_history(
+        ctx: Context<FundingHistoryStatus>,
+        start: u16,
+    ) -> Result<FundingHistorySnapshot> {
+        funding_history_handler(ctx, start)
+    }
 }
@@ -1,15 +1,611 @@
+use crate::constants::*;
+use crate::errors::*;
+use anchor_lang::prelude::*;
+
 // Calculate price based on skew
 pub fn calculate_price_from_skew(base_price: u64, skew: i64, skew_scale: u64) -> u64 {
     let skew_adjustment = if skew >= 0 {
         (skew as i128 * base_price as i128) / skew_scale as i128
     } else {
-        -((skew.abs() as i128 * base_price as i128) / skew_scale as i128)
+        -((skew.unsigned_abs() as i128 * base_price as i128) / skew_scale as i128)
     };
 
     (base_price as i128 + skew_adjustment) as u64
 }
 
-// Calculate funding rate based on skew
-pub fn calculate_funding_rate(skew: i64, skew_scale: u64, max_funding_rate: u64) -> i64 {
-    ((skew as i128 * max_funding_rate as i128) / skew_scale as i128) as i64
+// Calculate the price a trade of `size` fills at when it moves the skew from `skew` to
+// `skew + size`: the base price plus the premium at the average of the two skews. Rounded
+// against the trader, up when buying and down when selling.
+pub fn calculate_fill_price(base_price: u64, skew: i64, size: i64, skew_scale: u64) -> u64 {
+    let premium = (2 * skew as i128 + size as i128) * base_price as i128;
+    let denominator = 2 * skew_scale as i128;
+
+    let premium = if size > 0 {
+        -(-premium).div_euclid(denominator)
+    } else {
+        premium.div_euclid(denominator)
+    };
+
+    (base_price as i128 + premium).max(0) as u64
+}
+
+// Calculate funding rate based on skew, as a daily fraction scaled by FUNDING_INDEX_DECIMALS
+pub fn calculate_funding_rate(skew: i64, skew_scale: u64, max_funding_rate: u64) -> i128 {
+    skew as i128 * max_funding_rate as i128 * FUNDING_INDEX_DECIMALS as i128
+        / (skew_scale as i128 * PERCENTAGE_DECIMALS as i128)
+}
+
+// Calculate how fast the funding rate drifts under the velocity model, per day and scaled
+// like the rate. The skew is capped at skew_scale so the velocity never exceeds the max.
+pub fn calculate_funding_velocity(skew: i64, skew_scale: u64, max_funding_velocity: u64) -> i128 {
+    let max_velocity =
+        max_funding_velocity as i128 * FUNDING_INDEX_DECIMALS as i128 / PERCENTAGE_DECIMALS as i128;
+
+    (skew as i128 * max_velocity / skew_scale as i128).clamp(-max_velocity, max_velocity)
+}
+
+// Calculate the funding a position owes (positive) or is owed (negative) for a move of the
+// funding index: longs pay while the index rises, shorts while it falls
+pub fn calculate_funding_owed(size: i64, entry_price: u64, funding_index_delta: i128) -> i128 {
+    // Funding amount = position notional * funding index delta
+    let position_notional =
+        size.unsigned_abs() as i128 * entry_price as i128 / PRICE_DECIMALS as i128;
+    let funding_amount = position_notional * funding_index_delta / FUNDING_INDEX_DECIMALS as i128;
+
+    if size > 0 {
+        funding_amount
+    } else {
+        -funding_amount
+    }
+}
+
+// Calculate the premium of the skew-adjusted mark price over the oracle price, as a fraction
+// scaled by FUNDING_INDEX_DECIMALS
+pub fn calculate_premium(oracle_price: u64, skew: i64, skew_scale: u64) -> i128 {
+    if oracle_price == 0 {
+        return 0;
+    }
+
+    let mark_price = calculate_price_from_skew(oracle_price, skew, skew_scale);
+
+    (mark_price as i128 - oracle_price as i128) * FUNDING_INDEX_DECIMALS as i128
+        / oracle_price as i128
+}
+
+// Calculate the daily funding rate of the premium index model from the average premium over
+// the period: premium + clamp(interest - premium, +-premium_clamp), where the interest rate
+// and clamp apply per funding interval, capped at +-max_funding_rate. Scaled like
+// calculate_funding_rate.
+pub fn calculate_premium_funding_rate(
+    average_premium: i128,
+    interest_rate: u64,
+    premium_clamp: u64,
+    max_funding_rate: u64,
+) -> i128 {
+    let to_rate = |percentage: u64| {
+        percentage as i128 * FUNDING_INDEX_DECIMALS as i128 / PERCENTAGE_DECIMALS as i128
+    };
+    let premium_clamp = to_rate(premium_clamp);
+    let max_funding_rate = to_rate(max_funding_rate);
+
+    let interval_rate = average_premium
+        + (to_rate(interest_rate) - average_premium).clamp(-premium_clamp, premium_clamp);
+
+    // Paid once per funding interval, so a day has SECONDS_IN_DAY / FUNDING_INTERVAL of them
+    (interval_rate * SECONDS_IN_DAY as i128 / FUNDING_INTERVAL as i128)
+        .clamp(-max_funding_rate, max_funding_rate)
+}
+
+// Drift `funding_rate` at `velocity` for `elapsed` seconds, capped at +-`max_funding_rate`
+// (both scaled like calculate_funding_rate). Returns the new rate and the funding index
+// increment, which is the rate integrated over the elapsed time.
+pub fn calculate_velocity_funding(
+    funding_rate: i128,
+    velocity: i128,
+    max_funding_rate: i128,
+    elapsed: i64,
+) -> (i128, i128) {
+    let elapsed = elapsed as i128;
+    let day = SECONDS_IN_DAY as i128;
+
+    let unclamped_rate = funding_rate + velocity * elapsed / day;
+    let new_rate = unclamped_rate.clamp(-max_funding_rate, max_funding_rate);
+
+    // The rate drifts linearly until it reaches the cap and stays there for the rest
+    let drift_secs = if new_rate == unclamped_rate || velocity == 0 {
+        elapsed
+    } else {
+        ((new_rate - funding_rate) * day / velocity).clamp(0, elapsed)
+    };
+    let integral = (funding_rate + new_rate) * drift_secs / 2 + new_rate * (elapsed - drift_secs);
+
+    (new_rate, integral / day)
+}
+
+// Calculate PnL of a leveraged position moving from entry_price to exit_price
+pub fn calculate_pnl(size: i64, leverage: u8, entry_price: u64, exit_price: u64) -> Result<i64> {
+    let entry_value = calculate_notional_u128(size, leverage, entry_price)? as i128;
+    let exit_value = calculate_notional_u128(size, leverage, exit_price)? as i128;
+
+    let pnl = if size > 0 {
+        // Long position: profit if exit_value > entry_value
+        exit_value - entry_value
+    } else {
+        // Short position: profit if entry_value > exit_value
+        entry_value - exit_value
+    };
+
+    i64::try_from(pnl).map_err(|_| error!(DErrorCode::MathOverflow))
+}
+
+// Calculate the fee for changing a market's skew by `size` at `price`. The part of the trade
+// that moves the skew towards zero pays the maker fee, anything beyond it pays the taker fee.
+pub fn calculate_trade_fee(
+    skew: i64,
+    size: i64,
+    leverage: u8,
+    price: u64,
+    maker_fee: u64,
+    taker_fee: u64,
+) -> Result<u64> {
+    let trade_size = size.unsigned_abs() as u128;
+    let reducing_size = if skew.signum() * size.signum() < 0 {
+        std::cmp::min(trade_size, skew.unsigned_abs() as u128)
+    } else {
+        0
+    };
+    let increasing_size = trade_size - reducing_size;
+
+    let fee_size = reducing_size * maker_fee as u128 + increasing_size * taker_fee as u128;
+
+    let fee = fee_size
+        .checked_mul(leverage as u128 * price as u128)
+        .ok_or(DErrorCode::MathOverflow)?
+        / PRICE_DECIMALS
+        / PERCENTAGE_DECIMALS as u128;
+
+    u64::try_from(fee).map_err(|_| error!(DErrorCode::MathOverflow))
+}
+
+// Reduce a trading fee by a discount (percentage with 4 decimals)
+pub fn apply_fee_discount(fee: u64, discount: u64) -> u64 {
+    (fee as u128 * (PERCENTAGE_DECIMALS - discount) as u128 / PERCENTAGE_DECIMALS as u128) as u64
+}
+
+// Calculate the haircut applied to a winning position's PnL for losses socialized since it opened
+pub fn calculate_socialized_haircut(index_delta: u128, size: i64, pnl: i64) -> u64 {
+    if pnl <= 0 {
+        return 0;
+    }
+
+    let haircut = index_delta * size.unsigned_abs() as u128 / SOCIALIZED_LOSS_INDEX_DECIMALS;
+
+    std::cmp::min(haircut, pnl as u128) as u64
+}
+
+fn calculate_notional_u128(size: i64, leverage: u8, price: u64) -> Result<u128> {
+    (size.unsigned_abs() as u128 * leverage as u128)
+        .checked_mul(price as u128)
+        .map(|value| value / PRICE_DECIMALS)
+        .ok_or(error!(DErrorCode::MathOverflow))
+}
+
+// Calculate the notional value of a leveraged position of `size` at `price`
+pub fn calculate_notional(size: i64, leverage: u8, price: u64) -> Result<u64> {
+    u64::try_from(calculate_notional_u128(size, leverage, price)?)
+        .map_err(|_| error!(DErrorCode::MathOverflow))
+}
+
+// Calculate the margin backing a position for a given requirement (percentage with 4 decimals)
+pub fn calculate_margin(size: i64, leverage: u8, price: u64, requirement: u64) -> Result<u64> {
+    if leverage == 0 {
+        return Ok(0);
+    }
+
+    let margin = calculate_notional_u128(size, leverage, price)?
+        .checked_mul(requirement as u128)
+        .ok_or(DErrorCode::MathOverflow)?
+        / PERCENTAGE_DECIMALS as u128
+        / leverage as u128;
+
+    u64::try_from(margin).map_err(|_| error!(DErrorCode::MathOverflow))
+}
+
+// Calculate the fraction (4 decimal places) of a position to close so that the account ends
+// above its maintenance margin plus a buffer, at most max_liquidation_fraction per call.
+// Closing a fraction releases that share of the position's maintenance margin while the
+// liquidation fee is the only equity removed.
+pub fn calculate_liquidation_fraction(
+    equity: i128,
+    account_maintenance_margin: u64,
+    position_maintenance_margin: u64,
+    position_liquidation_fee: u64,
+    liquidation_buffer: u64,
+    max_liquidation_fraction: u64,
+) -> u64 {
+    // A bankrupt account cannot be restored, close as much as a single call may
+    if equity <= 0 {
+        return max_liquidation_fraction;
+    }
+
+    let buffer_factor = (PERCENTAGE_DECIMALS + liquidation_buffer) as i128;
+    let target_margin =
+        account_maintenance_margin as i128 * buffer_factor / PERCENTAGE_DECIMALS as i128;
+    let deficit = target_margin - equity;
+    if deficit <= 0 {
+        return 0;
+    }
+
+    let relief_per_position = position_maintenance_margin as i128 * buffer_factor
+        / PERCENTAGE_DECIMALS as i128
+        - position_liquidation_fee as i128;
+
+    let fraction = if relief_per_position <= 0 {
+        PERCENTAGE_DECIMALS
+    } else {
+        // Round up so the account is not left just below the target
+        let fraction =
+            (deficit * PERCENTAGE_DECIMALS as i128 + relief_per_position - 1) / relief_per_position;
+        std::cmp::min(fraction, PERCENTAGE_DECIMALS as i128) as u64
+    };
+
+    std::cmp::min(fraction, max_liquidation_fraction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRICE: u64 = 2_000_000_000; // $2,000
+    const SKEW_SCALE: u64 = 1_000_000_000;
+
+    // (skew before the trade, trade size) for buys and sells, with and against the skew
+    const TRADES: [(i64, i64); 8] = [
+        (0, 100_000_000),
+        (0, -100_000_000),
+        (250_000_000, 3_333_333),
+        (250_000_000, -400_000_001),
+        (-250_000_000, 400_000_001),
+        (-250_000_000, -7),
+        (999, 1),
+        (-1, -999),
+    ];
+
+    #[test]
+    fn fill_price_averages_skew_before_and_after() {
+        // From 0 to 0.1 of the skew scale fills at the 0.05 premium
+        assert_eq!(
+            calculate_fill_price(PRICE, 0, 100_000_000, SKEW_SCALE),
+            2_100_000_000
+        );
+        // Closing it again fills at the same price
+        assert_eq!(
+            calculate_fill_price(PRICE, 100_000_000, -100_000_000, SKEW_SCALE),
+            2_100_000_000
+        );
+        // A trade that flips the skew from -0.1 to 0.1 averages out to no premium
+        assert_eq!(
+            calculate_fill_price(PRICE, -100_000_000, 200_000_000, SKEW_SCALE),
+            PRICE
+        );
+    }
+
+    #[test]
+    fn fill_price_rounds_against_the_trader() {
+        // A premium of half a unit is charged to buyers and withheld from sellers
+        assert_eq!(calculate_fill_price(1, 0, 1, 1), 2);
+        assert_eq!(calculate_fill_price(1, 1, -1, 1), 1);
+        assert_eq!(calculate_fill_price(3, 0, 1, 4), 4);
+        assert_eq!(calculate_fill_price(3, 1, -1, 4), 3);
+    }
+
+    #[test]
+    fn round_trip_cannot_extract_value() {
+        for (skew, size) in TRADES {
+            for leverage in [1, 10] {
+                let entry_price = calculate_fill_price(PRICE, skew, size, SKEW_SCALE);
+                let exit_price = calculate_fill_price(PRICE, skew + size, -size, SKEW_SCALE);
+
+                let pnl = calculate_pnl(size, leverage, entry_price, exit_price).unwrap();
+                assert!(
+                    pnl <= 0,
+                    "skew={} size={} leverage={} pnl={}",
+                    skew,
+                    size,
+                    leverage,
+                    pnl
+                );
+            }
+        }
+    }
+
+    // Exact value a trader gains buying (positive size) or selling at entry_price and unwinding
+    // at exit_price, before calculate_pnl truncates it to whole units
+    fn trade_value(size: i64, entry_price: u64, exit_price: u64) -> i128 {
+        size as i128 * (exit_price as i128 - entry_price as i128)
+    }
+
+    #[test]
+    fn splitting_a_round_trip_cannot_extract_value() {
+        // Opening in many small trades and closing in one, or the other way round, is priced
+        // along the same skew path as a single trade
+        for (skew, size) in TRADES {
+            for chunks in [2, 3, 10] {
+                let chunk = size / chunks;
+                let sizes: Vec<i64> = (0..chunks)
+                    .map(|i| {
+                        if i == chunks - 1 {
+                            size - chunk * (chunks - 1)
+                        } else {
+                            chunk
+                        }
+                    })
+                    .collect();
+
+                // Split open, single close
+                let mut current_skew = skew;
+                let mut entries = Vec::new();
+                for chunk_size in sizes.iter() {
+                    entries.push(calculate_fill_price(
+                        PRICE,
+                        current_skew,
+                        *chunk_size,
+                        SKEW_SCALE,
+                    ));
+                    current_skew += chunk_size;
+                }
+                let exit_price = calculate_fill_price(PRICE, current_skew, -size, SKEW_SCALE);
+                let value: i128 = sizes
+                    .iter()
+                    .zip(entries.iter())
+                    .map(|(chunk_size, entry_price)| {
+                        trade_value(*chunk_size, *entry_price, exit_price)
+                    })
+                    .sum();
+                assert!(
+                    value <= 0,
+                    "split open skew={} size={} value={}",
+                    skew,
+                    size,
+                    value
+                );
+
+                // Single open, split close
+                let entry_price = calculate_fill_price(PRICE, skew, size, SKEW_SCALE);
+                let mut current_skew = skew + size;
+                let mut value = 0;
+                for chunk_size in sizes.iter() {
+                    let exit_price =
+                        calculate_fill_price(PRICE, current_skew, -chunk_size, SKEW_SCALE);
+                    value += trade_value(*chunk_size, entry_price, exit_price);
+                    current_skew -= chunk_size;
+                }
+                assert!(
+                    value <= 0,
+                    "split close skew={} size={} value={}",
+                    skew,
+                    size,
+                    value
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn funding_velocity_is_capped_at_the_skew_scale() {
+        // Up to 3% a day of drift
+        let max_velocity = 30_000_000_000;
+        assert_eq!(
+            calculate_funding_velocity(500_000_000, SKEW_SCALE, 300),
+            max_velocity / 2
+        );
+        assert_eq!(
+            calculate_funding_velocity(2_000_000_000, SKEW_SCALE, 300),
+            max_velocity
+        );
+        assert_eq!(
+            calculate_funding_velocity(-2_000_000_000, SKEW_SCALE, 300),
+            -max_velocity
+        );
+    }
+
+    #[test]
+    fn velocity_funding_integrates_the_drifting_rate() {
+        // Rates are daily and scaled by FUNDING_INDEX_DECIMALS: 1_000_000_000 is 0.1% a day,
+        // capped at 1% a day
+        let day = SECONDS_IN_DAY as i64;
+        let max_rate = 10_000_000_000;
+
+        // Drifting from 0.1% to 0.4% over a day accrues the 0.25% average
+        assert_eq!(
+            calculate_velocity_funding(1_000_000_000, 3_000_000_000, max_rate, day),
+            (4_000_000_000, 2_500_000_000)
+        );
+        // Over an hour it drifts by a 24th, and accrues the average rate for an hour
+        assert_eq!(
+            calculate_velocity_funding(1_000_000_000, 3_000_000_000, max_rate, 3_600),
+            (1_125_000_000, 44_270_833)
+        );
+        // Without velocity the rate stays put
+        assert_eq!(
+            calculate_velocity_funding(1_000_000_000, 0, max_rate, day),
+            (1_000_000_000, 1_000_000_000)
+        );
+    }
+
+    #[test]
+    fn velocity_funding_stops_drifting_at_the_max_rate() {
+        let day = SECONDS_IN_DAY as i64;
+        let max_rate = 10_000_000_000;
+
+        // The cap is reached after a quarter of the day and held for the rest of it
+        assert_eq!(
+            calculate_velocity_funding(0, 4 * max_rate, max_rate, day),
+            (max_rate, max_rate * 7 / 8)
+        );
+        assert_eq!(
+            calculate_velocity_funding(0, -4 * max_rate, max_rate, day),
+            (-max_rate, -max_rate * 7 / 8)
+        );
+        // A rate already at the cap stays there
+        assert_eq!(
+            calculate_velocity_funding(max_rate, 4 * max_rate, max_rate, day),
+            (max_rate, max_rate)
+        );
+    }
+
+    #[test]
+    fn velocity_funding_changes_sign_mid_interval() {
+        let day = SECONDS_IN_DAY as i64;
+        let max_rate = 10_000_000_000;
+
+        // Shorts are paid while the rate is negative and pay once it turns positive, which
+        // cancels out over a day drifting from -0.1% to 0.1%
+        assert_eq!(
+            calculate_velocity_funding(-1_000_000_000, 2_000_000_000, max_rate, day),
+            (1_000_000_000, 0)
+        );
+        // Half way through, only the negative half has accrued
+        assert_eq!(
+            calculate_velocity_funding(-1_000_000_000, 2_000_000_000, max_rate, day / 2),
+            (0, -250_000_000)
+        );
+        // From one cap to the other, the drift cancels out and the rest accrues at the cap
+        assert_eq!(
+            calculate_velocity_funding(-max_rate, 4 * max_rate, max_rate, day),
+            (max_rate, max_rate / 2)
+        );
+    }
+
+    #[test]
+    fn premium_funding_rate_pays_interest_within_the_clamp() {
+        // 0.01% interest and a 0.05% clamp per interval, at most 1% a day
+        let rate = |premium| calculate_premium_funding_rate(premium, 1, 5, 100);
+        let interval = |percentage: i128| percentage * FUNDING_INDEX_DECIMALS as i128 / 10_000;
+        let daily = |interval_rate: i128| interval_rate * 24;
+
+        // Without a premium, or with one within the clamp of the interest rate, only the
+        // interest rate is paid
+        assert_eq!(rate(0), daily(interval(1)));
+        assert_eq!(rate(interval(3)), daily(interval(1)));
+        assert_eq!(rate(-interval(4)), daily(interval(1)));
+
+        // Beyond the clamp the premium is paid less the clamp, in either direction
+        assert_eq!(rate(interval(8)), daily(interval(3)));
+        assert_eq!(rate(-interval(8)), daily(-interval(3)));
+
+        // Capped at the max funding rate
+        assert_eq!(rate(interval(10)), interval(100));
+        assert_eq!(rate(-interval(10)), -interval(100));
+    }
+
+    #[test]
+    fn liquidation_closes_only_what_restores_the_buffer() {
+        // Maintenance margin of 1,000 with a 10% buffer targets 1,100 of equity. Closing the
+        // whole position releases 1,100 of buffered margin for a 500 fee, 600 of relief.
+        let fraction = |equity, fee, max| {
+            calculate_liquidation_fraction(equity, 1_000, 1_000, fee, 1_000, max)
+        };
+
+        // 100 short of the target takes a sixth of the position, rounded up
+        assert_eq!(fraction(1_000, 500, 5_000), 1_667);
+
+        // 600 short would take all of it, but one call closes at most half
+        assert_eq!(fraction(500, 500, 5_000), 5_000);
+        assert_eq!(fraction(500, 500, PERCENTAGE_DECIMALS), PERCENTAGE_DECIMALS);
+
+        // Nothing to close once the buffer is covered
+        assert_eq!(fraction(1_100, 500, 5_000), 0);
+    }
+
+    #[test]
+    fn liquidation_fraction_is_capped_when_closing_cannot_help() {
+        // A fee eating all of the released margin gives no relief
+        assert_eq!(
+            calculate_liquidation_fraction(1_000, 1_000, 1_000, 1_100, 1_000, 5_000),
+            5_000
+        );
+
+        // Neither can a bankrupt account be restored, it is unwound over several calls
+        assert_eq!(
+            calculate_liquidation_fraction(0, 1_000, 1_000, 500, 1_000, 5_000),
+            5_000
+        );
+        assert_eq!(
+            calculate_liquidation_fraction(-1_000, 1_000, 1_000, 500, 1_000, PERCENTAGE_DECIMALS),
+            PERCENTAGE_DECIMALS
+        );
+    }
+
+    #[test]
+    fn notional_rejects_overflow() {
+        // The largest size at a price of 1 fits in u64 at 2x leverage but not at 3x
+        assert_eq!(
+            calculate_notional(i64::MAX, 2, 1_000_000).unwrap(),
+            u64::MAX - 1
+        );
+        assert_eq!(
+            calculate_notional(i64::MAX, 3, 1_000_000).unwrap_err(),
+            error!(DErrorCode::MathOverflow)
+        );
+        // Even the u128 intermediate overflows here
+        assert!(calculate_notional(i64::MIN, u8::MAX, u64::MAX).is_err());
+        assert!(calculate_margin(i64::MIN, u8::MAX, u64::MAX, 1_000).is_err());
+    }
+
+    #[test]
+    fn margin_is_not_truncated_past_a_u64_notional() {
+        // A huge buy into an empty market fills far above the oracle price, pushing its
+        // notional just past u64::MAX
+        let size = 19_210_000_000_000;
+        let entry_price = calculate_fill_price(100_000_000, 0, size, SKEW_SCALE);
+        let notional = size as u128 * entry_price as u128 / PRICE_DECIMALS;
+        assert!(notional > u64::MAX as u128);
+        assert_eq!(
+            calculate_notional(size, 1, entry_price).unwrap_err(),
+            error!(DErrorCode::MathOverflow)
+        );
+
+        // The margin is still charged on the full notional, as long as it fits itself
+        assert_eq!(
+            calculate_margin(size, 1, entry_price, 1_000).unwrap() as u128,
+            notional / 10
+        );
+        assert_eq!(
+            calculate_margin(size, 1, entry_price, PERCENTAGE_DECIMALS).unwrap_err(),
+            error!(DErrorCode::MathOverflow)
+        );
+    }
+
+    #[test]
+    fn pnl_rejects_overflow_instead_of_wrapping() {
+        assert_eq!(
+            calculate_pnl(1_000_000, 1, 0, i64::MAX as u64).unwrap(),
+            i64::MAX
+        );
+        // A profit just past i64::MAX used to wrap into a loss
+        assert_eq!(
+            calculate_pnl(1_000_000, 1, 0, i64::MAX as u64 + 1).unwrap_err(),
+            error!(DErrorCode::MathOverflow)
+        );
+        assert_eq!(
+            calculate_pnl(-1_000_000, 1, u64::MAX, 0).unwrap_err(),
+            error!(DErrorCode::MathOverflow)
+        );
+        assert!(calculate_pnl(i64::MAX, u8::MAX, 0, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn trade_fee_rejects_overflow() {
+        // The full taker fee on the largest size fits at a price of 2 but not 3
+        assert_eq!(
+            calculate_trade_fee(0, i64::MAX, 1, 2_000_000, 0, PERCENTAGE_DECIMALS).unwrap(),
+            u64::MAX - 1
+        );
+        assert_eq!(
+            calculate_trade_fee(0, i64::MAX, 1, 3_000_000, 0, PERCENTAGE_DECIMALS).unwrap_err(),
+            error!(DErrorCode::MathOverflow)
+        );
+        assert!(calculate_trade_fee(0, i64::MAX, 10, u64::MAX, 0, PERCENTAGE_DECIMALS).is_err());
+    }
 }
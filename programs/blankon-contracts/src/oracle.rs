@@ -0,0 +1,146 @@
+use crate::constants::*;
+use crate::errors::*;
+use crate::state::*;
+use anchor_lang::prelude::*;
+
+pub struct OraclePrice {
+    pub price: u64,        // Normalized to 6 decimals
+    pub conf: u64,         // Confidence interval, normalized to 6 decimals
+    pub publish_time: i64, // Unix timestamp the price was published at
+}
+
+impl OraclePrice {
+    pub fn is_stale(&self, current_time: i64, max_price_age_secs: u64) -> bool {
+        self.publish_time.saturating_add(max_price_age_secs as i64) < current_time
+    }
+
+    // Confidence interval relative to the price, percentage with 4 decimal places
+    pub fn confidence_ratio(&self) -> u64 {
+        if self.price == 0 {
+            return u64::MAX;
+        }
+
+        (self.conf as u128 * PERCENTAGE_DECIMALS as u128 / self.price as u128) as u64
+    }
+
+    // Fresh and precise enough to trade against under `params`
+    pub fn is_usable(&self, current_time: i64, params: &MarketParams) -> bool {
+        !self.is_stale(current_time, params.max_price_age_secs)
+            && self.confidence_ratio() <= params.max_confidence_ratio
+    }
+}
+
+// A feed a market can read its price from
+pub trait PriceSource {
+    // Latest price published for `market`, regardless of its age
+    fn latest_price(&self, market: &MarketInfo) -> Result<OraclePrice>;
+}
+
+impl PriceSource for PushedPrice {
+    fn latest_price(&self, _market: &MarketInfo) -> Result<OraclePrice> {
+        // A price that was never pushed has a zero publish time and is always stale
+        Ok(OraclePrice {
+            price: self.price,
+            conf: self.conf,
+            publish_time: self.publish_time,
+        })
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum OracleSource {
+    Primary,  // The market's Pyth feed
+    Fallback, // The market's pushed fallback price
+}
+
+pub struct OracleReading {
+    pub price: OraclePrice,
+    pub source: OracleSource,
+    pub deviation: Option<u64>, // Primary vs fallback, 4 decimals, when both are usable
+}
+
+// Pick the price a market trades against: the primary feed while it is usable, otherwise the
+// fallback if that one is. Never fails on staleness so views can report it.
+pub fn read_oracle_price(
+    market: &MarketInfo,
+    primary: &impl PriceSource,
+    fallback: Option<&PushedPrice>,
+    current_time: i64,
+) -> Result<OracleReading> {
+    // A market with a fallback configured must always be read with it, otherwise a caller
+    // could skip the deviation check by leaving it out
+    require!(
+        fallback.is_some() == market.has_fallback_oracle(),
+        DErrorCode::InvalidOracleAccount
+    );
+
+    let primary_price = primary.latest_price(market)?;
+    let fallback_price = match fallback {
+        Some(fallback) => fallback.latest_price(market)?,
+        None => {
+            return Ok(OracleReading {
+                price: primary_price,
+                source: OracleSource::Primary,
+                deviation: None,
+            })
+        }
+    };
+
+    let primary_usable = primary_price.is_usable(current_time, &market.params);
+    if !fallback_price.is_usable(current_time, &market.params) {
+        return Ok(OracleReading {
+            price: primary_price,
+            source: OracleSource::Primary,
+            deviation: None,
+        });
+    }
+
+    if !primary_usable {
+        return Ok(OracleReading {
+            price: fallback_price,
+            source: OracleSource::Fallback,
+            deviation: None,
+        });
+    }
+
+    let deviation = (primary_price.price.abs_diff(fallback_price.price) as u128
+        * PERCENTAGE_DECIMALS as u128
+        / primary_price.price as u128) as u64;
+
+    Ok(OracleReading {
+        price: primary_price,
+        source: OracleSource::Primary,
+        deviation: Some(deviation),
+    })
+}
+
+// Read the price for trading, rejecting stale prices and sources that disagree too much
+pub fn get_oracle_price(
+    market: &MarketInfo,
+    primary: &impl PriceSource,
+    fallback: Option<&PushedPrice>,
+) -> Result<OraclePrice> {
+    let current_time = Clock::get()?.unix_timestamp;
+    let reading = read_oracle_price(market, primary, fallback, current_time)?;
+
+    require!(
+        !reading
+            .price
+            .is_stale(current_time, market.params.max_price_age_secs),
+        DErrorCode::StaleOraclePrice
+    );
+    require!(
+        reading.deviation.unwrap_or(0) <= market.params.max_oracle_deviation,
+        DErrorCode::OracleDeviationTooLarge
+    );
+
+    if reading.source == OracleSource::Fallback {
+        msg!(
+            "Primary oracle unusable for market {}, using fallback price {}",
+            market.market_index,
+            reading.price.price
+        );
+    }
+
+    Ok(reading.price)
+}
@@ -0,0 +1,174 @@
+use crate::constants::*;
+use crate::errors::*;
+use anchor_lang::prelude::*;
+
+// How to treat the digits dropped when a value has more decimals than PRICE_EXPONENT
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Rounding {
+    Down,    // Truncate towards zero
+    Up,      // Round away from zero, for values that should err on the conservative side
+    Nearest, // Round half up
+}
+
+// Normalize an oracle price `price * 10^expo` to PRICE_EXPONENT decimals. Non-positive prices,
+// including ones that round down to zero, are rejected.
+pub fn normalize_price(price: i64, expo: i32, rounding: Rounding) -> Result<u64> {
+    require!(price > 0, DErrorCode::InvalidOraclePrice);
+
+    let normalized = normalize_value(price as u64, expo, rounding)?;
+    require!(normalized > 0, DErrorCode::InvalidOraclePrice);
+
+    Ok(normalized)
+}
+
+// Normalize an unsigned oracle value `value * 10^expo` (e.g. a confidence interval) to
+// PRICE_EXPONENT decimals
+pub fn normalize_value(value: u64, expo: i32, rounding: Rounding) -> Result<u64> {
+    // Number of decimal digits to add (positive) or drop (negative)
+    let shift = expo as i64 - PRICE_EXPONENT as i64;
+
+    let normalized = if shift >= 0 {
+        let multiplier = pow10(shift)?;
+        (value as u128)
+            .checked_mul(multiplier)
+            .ok_or(DErrorCode::MathOverflow)?
+    } else {
+        // Dropping more than 38 digits leaves nothing of any u64
+        let divisor = match pow10(-shift) {
+            Ok(divisor) => divisor,
+            Err(_) => {
+                return Ok(if rounding == Rounding::Up && value > 0 {
+                    1
+                } else {
+                    0
+                })
+            }
+        };
+        let quotient = value as u128 / divisor;
+        let remainder = value as u128 % divisor;
+
+        match rounding {
+            Rounding::Down => quotient,
+            Rounding::Up if remainder > 0 => quotient + 1,
+            Rounding::Up => quotient,
+            Rounding::Nearest if remainder * 2 >= divisor => quotient + 1,
+            Rounding::Nearest => quotient,
+        }
+    };
+
+    u64::try_from(normalized).map_err(|_| error!(DErrorCode::MathOverflow))
+}
+
+fn pow10(exponent: i64) -> Result<u128> {
+    let exponent = u32::try_from(exponent).map_err(|_| error!(DErrorCode::MathOverflow))?;
+    10_u128
+        .checked_pow(exponent)
+        .ok_or(error!(DErrorCode::MathOverflow))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_price_across_exponents() {
+        // (price, expo, expected) for $123.45 at varying precisions
+        let cases: [(i64, i32, u64); 8] = [
+            (12_345_000_000, -8, 123_450_000),
+            (1_234_500_000, -7, 123_450_000),
+            (123_450_000, -6, 123_450_000),
+            (1_234_500, -4, 123_450_000),
+            (12_345, -2, 123_450_000),
+            (123, 0, 123_000_000),
+            (12, 1, 120_000_000),
+            (1, 3, 1_000_000_000),
+        ];
+
+        for (price, expo, expected) in cases {
+            assert_eq!(
+                normalize_price(price, expo, Rounding::Down).unwrap(),
+                expected,
+                "price={} expo={}",
+                price,
+                expo
+            );
+        }
+    }
+
+    #[test]
+    fn positive_exponents_scale_up() {
+        // 5 * 10^2 = $500, not $0.05 as the old expo.abs() logic produced
+        assert_eq!(normalize_price(5, 2, Rounding::Down).unwrap(), 500_000_000);
+    }
+
+    #[test]
+    fn rejects_non_positive_prices() {
+        for price in [0, -1, i64::MIN] {
+            let err = normalize_price(price, -8, Rounding::Down).unwrap_err();
+            assert_eq!(err, error!(DErrorCode::InvalidOraclePrice));
+        }
+    }
+
+    #[test]
+    fn rejects_prices_rounding_to_zero() {
+        let err = normalize_price(99, -8, Rounding::Down).unwrap_err();
+        assert_eq!(err, error!(DErrorCode::InvalidOraclePrice));
+        assert_eq!(normalize_price(99, -8, Rounding::Up).unwrap(), 1);
+    }
+
+    #[test]
+    fn rejects_overflow() {
+        let err = normalize_price(i64::MAX, 0, Rounding::Down).unwrap_err();
+        assert_eq!(err, error!(DErrorCode::MathOverflow));
+
+        let err = normalize_value(1, 40, Rounding::Down).unwrap_err();
+        assert_eq!(err, error!(DErrorCode::MathOverflow));
+
+        // The largest exponent that still fits for a price of 1
+        assert_eq!(
+            normalize_price(1, 13, Rounding::Down).unwrap(),
+            10_000_000_000_000_000_000
+        );
+        assert!(normalize_price(2, 13, Rounding::Down).is_err());
+    }
+
+    #[test]
+    fn applies_rounding_policy() {
+        // 1.2345675 with 7 decimals
+        assert_eq!(
+            normalize_value(12_345_675, -7, Rounding::Down).unwrap(),
+            1_234_567
+        );
+        assert_eq!(
+            normalize_value(12_345_675, -7, Rounding::Up).unwrap(),
+            1_234_568
+        );
+        assert_eq!(
+            normalize_value(12_345_675, -7, Rounding::Nearest).unwrap(),
+            1_234_568
+        );
+        assert_eq!(
+            normalize_value(12_345_674, -7, Rounding::Nearest).unwrap(),
+            1_234_567
+        );
+
+        // Exact values are unaffected by the rounding mode
+        for rounding in [Rounding::Down, Rounding::Up, Rounding::Nearest] {
+            assert_eq!(
+                normalize_value(12_345_600, -7, rounding).unwrap(),
+                1_234_560
+            );
+        }
+    }
+
+    #[test]
+    fn very_small_exponents_collapse() {
+        assert_eq!(normalize_value(u64::MAX, -50, Rounding::Down).unwrap(), 0);
+        assert_eq!(
+            normalize_value(u64::MAX, -50, Rounding::Nearest).unwrap(),
+            0
+        );
+        assert_eq!(normalize_value(1, -50, Rounding::Up).unwrap(), 1);
+        assert_eq!(normalize_value(0, -50, Rounding::Up).unwrap(), 0);
+    }
+}
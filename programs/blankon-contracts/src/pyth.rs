@@ -1,58 +1,104 @@
 use crate::errors::*;
+use crate::oracle::*;
+use crate::price::*;
+use crate::state::*;
 use anchor_lang::prelude::*;
-use pyth_solana_receiver_sdk::price_update::{get_feed_id_from_hex, PriceUpdateV2};
-
-pub const FEED_IDS: [&str; 3] = [
-    "0x765d2ba906dbc32ca17cc11f5310a89e9ee1f6420508c63861f2f8ba4ee34bb2",
-    "0xef0d8b6fda2ceba41da15d4095d1da392a0d2f8ed0c6c7bc0f4cfac8c280b56d",
-    "0xe62df6c8b4a85fe1a67db44dc12de5db330f7ac66b72dc658afedf0f4a415b43",
-];
-
-pub fn get_pyth_price<'info>(
-    pyth_price_account: &Account<'info, PriceUpdateV2>,
-    market: u8,
-) -> Result<u64> {
-    let feed_id = FEED_IDS
-        .get(market as usize)
-        .ok_or(error!(DErrorCode::InvalidAssetType))?;
-    let feed_id =
-        get_feed_id_from_hex(feed_id).map_err(|_| error!(DErrorCode::InvalidOracleAccount))?;
-
-    let price_update = pyth_price_account
-        .get_price_unchecked(&feed_id)
-        // .get_price_no_older_than(&Clock::get()?, 36000, &feed_id)
+use pyth_solana_receiver_sdk::price_update::{FeedId, Price, PriceUpdateV2, VerificationLevel};
+
+impl PriceSource for PriceUpdateV2 {
+    fn latest_price(&self, market: &MarketInfo) -> Result<OraclePrice> {
+        read_price_update(self, &market.pyth_feed_id)
+    }
+}
+
+// Read the price of `feed_id` regardless of its age. Like get_price_no_older_than, only updates
+// verified by the full Wormhole guardian set are accepted.
+fn read_price_update(price_update: &PriceUpdateV2, feed_id: &FeedId) -> Result<OraclePrice> {
+    require!(
+        price_update.verification_level.gte(VerificationLevel::Full),
+        DErrorCode::InvalidOracleAccount
+    );
+
+    let price = price_update
+        .get_price_unchecked(feed_id)
         .map_err(|_| error!(DErrorCode::InvalidOracleAccount))?;
 
-    // Convert price to a standard format (e.g., USD with 6 decimals)
-    // The price is represented as a fixed-point number with 'expo' number of decimal places
-    // We want to convert it to a u64 with 6 decimal places
-
-    // First, get the price as a signed integer
-    let price_value = price_update.price;
-
-    // Get the exponent (negative for decimal places)
-    let expo = price_update.exponent;
-
-    // Convert to a standard format with 6 decimal places
-    let normalized_price = if expo <= -6 {
-        // If expo is already more negative than -6, we need to divide
-        // For example, if expo is -8, we divide by 10^(8-6) = 10^2
-        let divisor = 10_i64.pow((expo.abs() - 6) as u32);
-        (price_value / divisor) as u64
-    } else {
-        // If expo is less negative than -6 or positive, we need to multiply
-        // For example, if expo is -4, we multiply by 10^(6-4) = 10^2
-        let multiplier = 10_i64.pow((6 - expo.abs()) as u32);
-        (price_value * multiplier) as u64
+    to_oracle_price(&price)
+}
+
+fn to_oracle_price(price_update: &Price) -> Result<OraclePrice> {
+    // Round the price to the nearest unit and the confidence up so uncertainty is never understated
+    let oracle_price = OraclePrice {
+        price: normalize_price(price_update.price, price_update.exponent, Rounding::Nearest)?,
+        conf: normalize_value(price_update.conf, price_update.exponent, Rounding::Up)?,
+        publish_time: price_update.publish_time,
     };
 
     // Log the price information for debugging
     msg!(
-        "Pyth price: raw={}, expo={}, normalized={}",
-        price_value,
-        expo,
-        normalized_price
+        "Pyth price: raw={}, conf={}, expo={}, normalized={}, normalized_conf={}",
+        price_update.price,
+        price_update.conf,
+        price_update.exponent,
+        oracle_price.price,
+        oracle_price.conf
     );
 
-    Ok(normalized_price as u64)
+    Ok(oracle_price)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyth_solana_receiver_sdk::price_update::PriceFeedMessage;
+
+    const FEED_ID: FeedId = [7; 32];
+
+    fn price_update(verification_level: VerificationLevel) -> PriceUpdateV2 {
+        PriceUpdateV2 {
+            write_authority: Pubkey::default(),
+            verification_level,
+            price_message: PriceFeedMessage {
+                feed_id: FEED_ID,
+                price: 12_345_000_000,
+                conf: 1_000_000,
+                exponent: -8,
+                publish_time: 900,
+                prev_publish_time: 899,
+                ema_price: 12_345_000_000,
+                ema_conf: 1_000_000,
+            },
+            posted_slot: 0,
+        }
+    }
+
+    #[test]
+    fn reads_fully_verified_updates() {
+        let oracle_price =
+            read_price_update(&price_update(VerificationLevel::Full), &FEED_ID).unwrap();
+        assert_eq!(oracle_price.price, 123_450_000);
+        assert_eq!(oracle_price.conf, 10_000);
+        assert_eq!(oracle_price.publish_time, 900);
+    }
+
+    #[test]
+    fn rejects_partially_verified_updates() {
+        for num_signatures in [1, 12] {
+            let err = read_price_update(
+                &price_update(VerificationLevel::Partial { num_signatures }),
+                &FEED_ID,
+            )
+            .err()
+            .unwrap();
+            assert_eq!(err, error!(DErrorCode::InvalidOracleAccount));
+        }
+    }
+
+    #[test]
+    fn rejects_other_feeds() {
+        let err = read_price_update(&price_update(VerificationLevel::Full), &[8; 32])
+            .err()
+            .unwrap();
+        assert_eq!(err, error!(DErrorCode::InvalidOracleAccount));
+    }
 }
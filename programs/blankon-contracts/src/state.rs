@@ -1,50 +1,775 @@
+use crate::constants::*;
+use crate::errors::*;
+use crate::math::calculate_premium;
 use anchor_lang::prelude::*;
 
 #[account]
 pub struct BlankonState {
     pub admin: Pubkey,
-    pub markets: [MarketInfo; 3], // Gold, SOL, BTC
+    pub market_count: u16, // Number of markets registered via add_market
+    pub insurance_fund_share: u64, // Share of liquidation penalties sent to the insurance fund, 4 decimals
+    pub cumulative_bad_debt: u64,  // Shortfalls the insurance fund could not cover or socialize
+    pub cumulative_socialized_loss: u64, // Shortfalls pushed onto the opposite side's PnL
+    pub mode: TradingMode,
+    pub paper_config: PaperConfig, // Only used in paper mode
+    pub collateral_mint: Pubkey,   // Default until initialize_vault configures a collateral vault
+    pub vault_bump: u8,
+    pub fee_pool: u64, // Trading fees charged and not yet collected by the admin
+    pub total_fees_collected: u64, // Cumulative trading fees collected by the admin
+    pub fee_tiers: [FeeTier; FEE_TIER_COUNT], // Volume discounts, ascending
+    pub referral_config: ReferralConfig,
 }
 
 impl BlankonState {
     pub const LEN: usize = std::mem::size_of::<Self>();
+
+    // Discount off trading fees (4 decimals) for a user with `rolling_volume` of 30 day notional
+    pub fn fee_discount(&self, rolling_volume: u64) -> u64 {
+        self.fee_tiers
+            .iter()
+            .rev()
+            .find(|tier| tier.discount > 0 && tier.min_volume <= rolling_volume)
+            .map_or(0, |tier| tier.discount)
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct ReferralConfig {
+    pub referrer_share: u64, // Share of a referred user's fees paid to the referrer, 4 decimals
+    pub referee_discount: u64, // Discount off a referred user's fees, 4 decimals
+}
+
+impl ReferralConfig {
+    pub fn validate(&self) -> Result<()> {
+        require!(
+            self.referrer_share <= PERCENTAGE_DECIMALS
+                && self.referee_discount <= PERCENTAGE_DECIMALS,
+            DErrorCode::InvalidConfiguration
+        );
+
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct FeeTier {
+    pub min_volume: u64, // Rolling 30 day notional needed for the tier
+    pub discount: u64,   // Off the market's trading fees, 4 decimals, 0 for an unused tier
+}
+
+impl FeeTier {
+    // Tiers must get more demanding and more generous in order, so the last one a user
+    // qualifies for is always the best
+    pub fn validate_table(fee_tiers: &[FeeTier; FEE_TIER_COUNT]) -> Result<()> {
+        for tier in fee_tiers.iter() {
+            require!(
+                tier.discount <= PERCENTAGE_DECIMALS,
+                DErrorCode::InvalidConfiguration
+            );
+        }
+        for tiers in fee_tiers.windows(2) {
+            require!(
+                tiers[0].min_volume <= tiers[1].min_volume
+                    && tiers[0].discount <= tiers[1].discount,
+                DErrorCode::InvalidConfiguration
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum TradingMode {
+    Paper,          // Synthetic balances credited by the program
+    Collateralized, // Balances backed by deposits into the collateral vault
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct PaperConfig {
+    pub starting_balance: u64, // Credited to new user accounts
+    pub faucet_amount: u64,    // Credited by each faucet_top_up
+    pub faucet_cooldown: i64,  // Seconds between faucet top-ups of the same user
+}
+
+impl PaperConfig {
+    pub fn validate(&self) -> Result<()> {
+        require!(self.starting_balance > 0, DErrorCode::InvalidConfiguration);
+        require!(self.faucet_cooldown >= 0, DErrorCode::InvalidConfiguration);
+
+        Ok(())
+    }
+}
+
+#[account]
+pub struct InsuranceFund {
+    pub balance: u64,
+    pub total_shortfall_covered: u64, // Cumulative shortfalls absorbed by the fund
+    pub bump: u8,
+}
+
+impl InsuranceFund {
+    pub const LEN: usize = std::mem::size_of::<Self>();
+
+    // Absorb as much of `shortfall` as the fund can and return the uncovered remainder
+    pub fn cover_shortfall(&mut self, shortfall: u64) -> u64 {
+        let covered = std::cmp::min(shortfall, self.balance);
+        self.balance -= covered;
+        self.total_shortfall_covered = self.total_shortfall_covered.saturating_add(covered);
+
+        shortfall - covered
+    }
+}
+
+#[account]
 pub struct MarketInfo {
-    pub asset_type: u8,
+    pub market_index: u16,
     pub pyth_price_account: Pubkey,
+    pub pyth_feed_id: [u8; 32],
+    pub fallback_oracle: Pubkey, // PushedPrice used when Pyth is unusable, default if none
     pub skew: i64,
     pub total_long_size: u64,
     pub total_short_size: u64,
     pub last_funding_time: i64,
     pub global_funding_index: i128, // Cumulative funding index
+    pub funding_rate: i128, // Rate as of last_funding_time, daily and scaled by FUNDING_INDEX_DECIMALS
+    pub premium_sum: i128,  // Sum of premium * seconds since last_history_time, premium index model
+    pub premium_last: i128, // Latest sampled premium, scaled by FUNDING_INDEX_DECIMALS
+    pub premium_last_sample: i64,
+    pub last_history_time: i64, // Last funding history record, trades do not move it
+    pub long_socialized_loss_index: u128, // Cumulative loss per unit of long size
+    pub short_socialized_loss_index: u128, // Cumulative loss per unit of short size
+    pub params: MarketParams,
+    pub bump: u8,
+    pub last_accepted_price: u64, // Reference price for the price band, 0 if none
+    pub last_accepted_price_time: i64,
+    pub halted: bool, // Set by the circuit breaker, blocks trading until cleared
+    pub halted_at: i64,
+    pub cumulative_price: u128, // Sum of price * seconds up to twap_last_update
+    pub twap_last_price: u64,   // Price in effect since twap_last_update
+    pub twap_last_update: i64,
+    pub twap_observations: [TwapObservation; TWAP_OBSERVATIONS], // Ring of accumulator checkpoints
+    pub twap_observation_index: u8,                              // Slot of the latest checkpoint
 }
 
 impl MarketInfo {
     pub const LEN: usize = std::mem::size_of::<Self>();
+
+    pub fn has_fallback_oracle(&self) -> bool {
+        self.fallback_oracle != Pubkey::default()
+    }
+
+    // Circuit breaker. Returns whether `price` may be traded against: a price that moved more
+    // than `price_band` from the last accepted one within `price_band_window_secs` halts the
+    // market instead. Halted markets reject trading until the cooldown elapses or an admin
+    // resumes them.
+    pub fn check_price_band(&mut self, price: u64, current_time: i64) -> Result<bool> {
+        if self.halted {
+            require!(!self.is_halted(current_time), DErrorCode::MarketHalted);
+            self.resume();
+        }
+
+        let within_window = self.last_accepted_price > 0
+            && current_time
+                <= self
+                    .last_accepted_price_time
+                    .saturating_add(self.params.price_band_window_secs as i64);
+
+        if within_window {
+            let deviation = (price.abs_diff(self.last_accepted_price) as u128
+                * PERCENTAGE_DECIMALS as u128
+                / self.last_accepted_price as u128) as u64;
+
+            if deviation > self.params.price_band {
+                self.halted = true;
+                self.halted_at = current_time;
+
+                msg!(
+                    "Market {} halted: price {} deviates {} from last accepted price {}",
+                    self.market_index,
+                    price,
+                    deviation,
+                    self.last_accepted_price
+                );
+
+                return Ok(false);
+            }
+        }
+
+        self.last_accepted_price = price;
+        self.last_accepted_price_time = current_time;
+
+        Ok(true)
+    }
+
+    // Halted and still within the circuit breaker cooldown
+    pub fn is_halted(&self, current_time: i64) -> bool {
+        self.halted
+            && current_time
+                < self
+                    .halted_at
+                    .saturating_add(self.params.circuit_breaker_cooldown_secs as i64)
+    }
+
+    // Accumulate the time-weighted price up to `current_time` and make `price` the current one
+    pub fn record_price(&mut self, price: u64, current_time: i64) {
+        self.cumulative_price = self.cumulative_price_at(current_time);
+        self.twap_last_price = price;
+        self.twap_last_update = current_time;
+
+        let latest = self.twap_observations[self.twap_observation_index as usize];
+        if latest.timestamp == 0 || current_time - latest.timestamp >= self.twap_spacing() {
+            self.twap_observation_index =
+                ((self.twap_observation_index as usize + 1) % TWAP_OBSERVATIONS) as u8;
+            self.twap_observations[self.twap_observation_index as usize] = TwapObservation {
+                timestamp: current_time,
+                cumulative_price: self.cumulative_price,
+            };
+        }
+    }
+
+    // Seconds between TWAP checkpoints, often enough for the ring to span the whole window
+    fn twap_spacing(&self) -> i64 {
+        (self.params.twap_window_secs / (TWAP_OBSERVATIONS as u64 - 1)).max(1) as i64
+    }
+
+    // Record a price sample from the permissionless funding crank. Trades record every price
+    // they fill at, the crank only fills in for markets without a sample in the last checkpoint
+    // spacing, so spamming it cannot pick which momentary prices the TWAP and premium index
+    // hold. Returns whether the price was recorded.
+    pub fn sample_crank_price(&mut self, price: u64, current_time: i64) -> bool {
+        if self.twap_last_update > 0 && current_time - self.twap_last_update < self.twap_spacing() {
+            return false;
+        }
+
+        self.record_price(price, current_time);
+        self.sample_premium(price, current_time);
+        true
+    }
+
+    fn cumulative_price_at(&self, current_time: i64) -> u128 {
+        let elapsed = (current_time - self.twap_last_update).max(0) as u128;
+        if self.twap_last_update == 0 {
+            return self.cumulative_price;
+        }
+
+        self.cumulative_price + self.twap_last_price as u128 * elapsed
+    }
+
+    // Time-weighted average price over the last `twap_window_secs`, or over the recorded
+    // history if it is shorter. None until prices were recorded before `current_time`.
+    pub fn twap(&self, current_time: i64) -> Option<u64> {
+        let window_start = current_time.saturating_sub(self.params.twap_window_secs as i64);
+        let recorded = self.twap_observations.iter().filter(|observation| {
+            observation.timestamp > 0 && observation.timestamp < current_time
+        });
+
+        // Newest checkpoint covering the full window, otherwise the oldest one
+        let start = recorded
+            .clone()
+            .filter(|observation| observation.timestamp <= window_start)
+            .max_by_key(|observation| observation.timestamp)
+            .or_else(|| recorded.min_by_key(|observation| observation.timestamp))?;
+
+        let elapsed = (current_time - start.timestamp) as u128;
+        Some(((self.cumulative_price_at(current_time) - start.cumulative_price) / elapsed) as u64)
+    }
+
+    // Base price margin checks value a position of `size` at: the worse of `spot_price` and the
+    // TWAP for its holder, so neither a spot wick nor a lagging TWAP overstates its equity
+    pub fn margin_price(&self, spot_price: u64, size: i64, current_time: i64) -> u64 {
+        let twap = self.twap(current_time).unwrap_or(spot_price);
+
+        if size > 0 {
+            std::cmp::min(spot_price, twap)
+        } else {
+            std::cmp::max(spot_price, twap)
+        }
+    }
+
+    // Premium summed over time up to `current_time`
+    pub fn premium_sum_at(&self, current_time: i64) -> i128 {
+        self.premium_sum
+            + self.premium_last * (current_time - self.premium_last_sample).max(0) as i128
+    }
+
+    // Sample the premium of the mark over `oracle_price` at the current skew. Called whenever
+    // the skew changes so the premium index reflects the skew that was actually in place.
+    pub fn sample_premium(&mut self, oracle_price: u64, current_time: i64) {
+        self.premium_sum = self.premium_sum_at(current_time);
+        self.premium_last = calculate_premium(oracle_price, self.skew, self.params.skew_scale);
+        self.premium_last_sample = current_time;
+    }
+
+    // Clear a halt, the next price is accepted as the new reference
+    pub fn resume(&mut self) {
+        self.halted = false;
+        self.halted_at = 0;
+        self.last_accepted_price = 0;
+        self.last_accepted_price_time = 0;
+    }
+
+    // Socialized loss index of the side a position of `size` is on
+    pub fn socialized_loss_index(&self, size: i64) -> u128 {
+        if size > 0 {
+            self.long_socialized_loss_index
+        } else {
+            self.short_socialized_loss_index
+        }
+    }
+
+    // Spread `loss` left by a bankrupt position of `bankrupt_size` over the open interest on
+    // the other side, who are the ones profiting from it. Returns the part that could not be
+    // socialized because the other side has no open interest.
+    pub fn socialize_loss(&mut self, bankrupt_size: i64, loss: u64) -> u64 {
+        let (open_interest, index) = if bankrupt_size > 0 {
+            (self.total_short_size, &mut self.short_socialized_loss_index)
+        } else {
+            (self.total_long_size, &mut self.long_socialized_loss_index)
+        };
+
+        if open_interest == 0 {
+            return loss;
+        }
+
+        *index += loss as u128 * SOCIALIZED_LOSS_INDEX_DECIMALS / open_interest as u128;
+
+        0
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct MarketParams {
+    pub initial_margin_requirement: u64, // Percentage with 4 decimal places
+    pub maintenance_margin_requirement: u64, // Percentage with 4 decimal places
+    pub max_leverage: u8,
+    pub skew_scale: u64,               // Skew scale for price impact calculations
+    pub max_funding_rate: u64,         // Percentage with 4 decimal places
+    pub liquidation_fee: u64,          // Keeper reward, percentage with 4 decimal places
+    pub liquidation_buffer: u64, // Margin kept above maintenance after liquidation, 4 decimals
+    pub max_liquidation_fraction: u64, // Max share of a position closed per call, 4 decimals
+    pub max_price_age_secs: u64, // Oldest oracle price accepted for trading
+    pub max_confidence_ratio: u64, // Widest oracle confidence / price accepted for opens, 4 decimals
+    pub max_oracle_deviation: u64, // Max primary vs fallback price difference, 4 decimals
+    pub price_band: u64, // Max move from the last accepted price before halting, 4 decimals
+    pub price_band_window_secs: u64, // How long an accepted price stays the band's reference
+    pub circuit_breaker_cooldown_secs: u64, // Halt duration unless an admin resumes earlier
+    pub twap_window_secs: u64, // Averaging window of the market's TWAP
+    pub funding_crank_reward: u64, // Paid from the fee pool per due funding crank, 0 for none
+    pub funding_model: FundingModel,
+    pub max_funding_velocity: u64, // Velocity model only, daily rate change at full skew, 4 decimals
+    pub interest_rate: u64,        // Premium index model only, per funding interval, 4 decimals
+    pub premium_clamp: u64,        // Premium index model only, max interest adjustment, 4 decimals
+    pub maker_fee: u64,            // Fee on the part of a trade that reduces skew, 4 decimals
+    pub taker_fee: u64,            // Fee on the part of a trade that increases skew, 4 decimals
+    pub insurance_fee_share: u64,  // Share of trading fees sent to the insurance fund, 4 decimals
+    pub max_position_size: u64,    // Largest position size, before leverage
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FundingModel {
+    Skew,         // Rate proportional to the instantaneous skew
+    Velocity,     // Skew drives how fast the rate drifts, as in Synthetix perps v2
+    PremiumIndex, // Average mark vs oracle premium plus interest, as on centralized exchanges
+}
+
+impl MarketParams {
+    pub fn validate(&self) -> Result<()> {
+        require!(
+            self.maintenance_margin_requirement > 0
+                && self.maintenance_margin_requirement < self.initial_margin_requirement
+                && self.initial_margin_requirement <= PERCENTAGE_DECIMALS,
+            DErrorCode::InvalidMarketParams
+        );
+        require!(
+            self.max_leverage > 0 && self.max_leverage <= MAX_LEVERAGE_LIMIT,
+            DErrorCode::InvalidMarketParams
+        );
+        require!(self.skew_scale > 0, DErrorCode::InvalidMarketParams);
+        require!(
+            self.max_funding_rate <= MAX_FUNDING_RATE_LIMIT,
+            DErrorCode::InvalidMarketParams
+        );
+        require!(
+            self.liquidation_fee < self.maintenance_margin_requirement,
+            DErrorCode::InvalidMarketParams
+        );
+        require!(
+            self.liquidation_buffer <= PERCENTAGE_DECIMALS,
+            DErrorCode::InvalidMarketParams
+        );
+        require!(
+            self.max_liquidation_fraction > 0
+                && self.max_liquidation_fraction <= PERCENTAGE_DECIMALS,
+            DErrorCode::InvalidMarketParams
+        );
+        require!(self.max_price_age_secs > 0, DErrorCode::InvalidMarketParams);
+        require!(
+            self.max_confidence_ratio > 0 && self.max_confidence_ratio <= PERCENTAGE_DECIMALS,
+            DErrorCode::InvalidMarketParams
+        );
+        require!(
+            self.max_oracle_deviation > 0 && self.max_oracle_deviation <= PERCENTAGE_DECIMALS,
+            DErrorCode::InvalidMarketParams
+        );
+        require!(self.price_band > 0, DErrorCode::InvalidMarketParams);
+        require!(
+            self.price_band_window_secs > 0 && self.circuit_breaker_cooldown_secs > 0,
+            DErrorCode::InvalidMarketParams
+        );
+        require!(self.twap_window_secs > 0, DErrorCode::InvalidMarketParams);
+        if self.funding_model == FundingModel::Velocity {
+            require!(
+                self.max_funding_velocity > 0 && self.max_funding_rate > 0,
+                DErrorCode::InvalidMarketParams
+            );
+        }
+        if self.funding_model == FundingModel::PremiumIndex {
+            require!(self.max_funding_rate > 0, DErrorCode::InvalidMarketParams);
+            require!(
+                self.interest_rate <= PERCENTAGE_DECIMALS
+                    && self.premium_clamp <= PERCENTAGE_DECIMALS,
+                DErrorCode::InvalidMarketParams
+            );
+        }
+        require!(
+            self.maker_fee <= self.taker_fee && self.taker_fee <= MAX_TRADING_FEE_LIMIT,
+            DErrorCode::InvalidMarketParams
+        );
+        require!(
+            self.insurance_fee_share <= PERCENTAGE_DECIMALS,
+            DErrorCode::InvalidMarketParams
+        );
+        require!(
+            self.max_position_size > 0 && self.max_position_size <= i64::MAX as u64,
+            DErrorCode::InvalidMarketParams
+        );
+
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct TwapObservation {
+    pub timestamp: i64,         // 0 for an unused slot
+    pub cumulative_price: u128, // MarketInfo::cumulative_price at `timestamp`
+}
+
+// Ring buffer of a market's latest funding updates, written by the funding crank
+#[account]
+pub struct FundingHistory {
+    pub market: Pubkey,
+    pub total_records: u64, // Updates written since the market was added
+    pub records: [FundingRecord; FUNDING_HISTORY_LEN],
+    pub bump: u8,
+}
+
+impl FundingHistory {
+    pub const LEN: usize = std::mem::size_of::<Self>();
+
+    pub fn push(&mut self, record: FundingRecord) {
+        self.records[(self.total_records % FUNDING_HISTORY_LEN as u64) as usize] = record;
+        self.total_records += 1;
+    }
+
+    // Up to `limit` records starting `start` records after the oldest one still kept
+    pub fn records_from(&self, start: usize, limit: usize) -> Vec<FundingRecord> {
+        let kept = std::cmp::min(self.total_records, FUNDING_HISTORY_LEN as u64) as usize;
+        let oldest = (self.total_records - kept as u64) as usize;
+
+        (oldest + start..oldest + kept)
+            .take(limit)
+            .map(|record| self.records[record % FUNDING_HISTORY_LEN])
+            .collect()
+    }
 }
 
-// Constants for asset types
-pub const GOLD: u8 = 0;
-pub const SOL: u8 = 1;
-pub const BTC: u8 = 2;
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct FundingRecord {
+    pub timestamp: i64,
+    pub funding_rate: i128,  // Daily, scaled by FUNDING_INDEX_DECIMALS
+    pub funding_index: i128, // Global funding index after the update
+    pub skew: i64,
+    pub oracle_price: u64,
+}
+
+// Referral code registered by a referrer, collecting their share of referred users' fees
+#[account]
+pub struct ReferralCode {
+    pub code: [u8; REFERRAL_CODE_LEN],
+    pub referrer: Pubkey,
+    pub referred_users: u64,    // User accounts created with this code
+    pub claimable_rewards: u64, // Fee share not yet claimed into the referrer's balance
+    pub total_rewards: u64,     // Cumulative fee share earned
+    pub bump: u8,
+}
+
+impl ReferralCode {
+    pub const LEN: usize = std::mem::size_of::<Self>();
+}
+
+// Fallback price for a market, pushed by an admin-appointed keeper
+#[account]
+pub struct PushedPrice {
+    pub market: Pubkey,
+    pub authority: Pubkey, // Only signer allowed to push prices
+    pub price: u64,        // Normalized to 6 decimals
+    pub conf: u64,         // Confidence interval, normalized to 6 decimals
+    pub publish_time: i64, // Time of the last push
+    pub bump: u8,
+}
+
+impl PushedPrice {
+    pub const LEN: usize = std::mem::size_of::<Self>();
+}
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
 pub struct Position {
-    pub size: i64,                // Positive for long, negative for short
-    pub entry_price: u64,         // Price at entry
-    pub leverage: u8,             // Leverage used for the position
-    pub last_funding_index: i128, // Last funding index applied to this position
+    pub market_index: u16,                // Market this position belongs to
+    pub size: i64,                        // Positive for long, negative for short
+    pub entry_price: u64,                 // Price at entry
+    pub leverage: u8,                     // Leverage used for the position
+    pub margin: u64,                      // Margin locked when the position was opened
+    pub last_socialized_loss_index: u128, // Socialized loss index when the position was opened
+    pub last_funding_index: i128,         // Last funding index applied to this position
+}
+
+impl Position {
+    pub const LEN: usize = std::mem::size_of::<Self>();
+
+    // A new position in `market`, starting from the market's current funding and socialized
+    // loss indexes so it only pays and absorbs what accrues after it opened
+    pub fn open(
+        market: &MarketInfo,
+        size: i64,
+        entry_price: u64,
+        leverage: u8,
+        margin: u64,
+    ) -> Self {
+        Self {
+            market_index: market.market_index,
+            size,
+            entry_price,
+            leverage,
+            margin,
+            last_socialized_loss_index: market.socialized_loss_index(size),
+            last_funding_index: market.global_funding_index,
+        }
+    }
 }
 
 #[account]
 pub struct UserAccount {
     pub owner: Pubkey,
-    pub balance: u64,             // $10,000 in lamports equivalent
-    pub positions: [Position; 3], // Gold, SOL, BTC positions
+    pub balance: u64,                              // $10,000 in lamports equivalent
+    pub positions: Vec<Position>,                  // Open positions, at most MAX_POSITIONS
+    pub last_faucet_time: i64,                     // Last paper-mode faucet top-up
+    pub volume_buckets: [u64; VOLUME_WINDOW_DAYS], // Traded notional per day, indexed by day
+    pub volume_last_day: i64,                      // Day of the latest recorded trade
+    pub referral_code: Pubkey, // ReferralCode the account was created with, default if none
 }
 
 impl UserAccount {
-    pub const LEN: usize = std::mem::size_of::<Self>();
+    pub const LEN: usize =
+        32 + 8 + 4 + MAX_POSITIONS * Position::LEN + 8 + VOLUME_WINDOW_DAYS * 8 + 8 + 32;
+
+    pub fn has_referrer(&self) -> bool {
+        self.referral_code != Pubkey::default()
+    }
+
+    // Add traded notional to today's bucket, clearing the buckets of days without trades
+    pub fn record_volume(&mut self, notional: u64, current_time: i64) {
+        let day = current_time / SECONDS_IN_DAY as i64;
+        let window = VOLUME_WINDOW_DAYS as i64;
+
+        for cleared in std::cmp::max(self.volume_last_day + 1, day - window + 1)..=day {
+            self.volume_buckets[(cleared % window) as usize] = 0;
+        }
+        self.volume_last_day = std::cmp::max(self.volume_last_day, day);
+
+        let bucket = &mut self.volume_buckets[(day % window) as usize];
+        *bucket = bucket.saturating_add(notional);
+    }
+
+    // Notional traded over the last VOLUME_WINDOW_DAYS days, today included
+    pub fn rolling_volume(&self, current_time: i64) -> u64 {
+        let day = current_time / SECONDS_IN_DAY as i64;
+        let window = VOLUME_WINDOW_DAYS as i64;
+
+        // Buckets after the latest trade still hold volume from before the window
+        (day - window + 1..=std::cmp::min(day, self.volume_last_day))
+            .filter(|bucket_day| *bucket_day > self.volume_last_day - window)
+            .fold(0u64, |volume, bucket_day| {
+                volume.saturating_add(self.volume_buckets[(bucket_day % window) as usize])
+            })
+    }
+
+    pub fn position(&self, market_index: u16) -> Option<&Position> {
+        self.positions
+            .iter()
+            .find(|position| position.market_index == market_index)
+    }
+
+    pub fn position_mut(&mut self, market_index: u16) -> Option<&mut Position> {
+        self.positions
+            .iter_mut()
+            .find(|position| position.market_index == market_index)
+    }
+
+    pub fn remove_position(&mut self, market_index: u16) {
+        self.positions
+            .retain(|position| position.market_index != market_index);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // A market as add_market creates it at time 0, with the default deployment's parameters
+    pub(crate) fn market() -> MarketInfo {
+        MarketInfo {
+            market_index: 0,
+            pyth_price_account: Pubkey::default(),
+            pyth_feed_id: [0; 32],
+            fallback_oracle: Pubkey::default(),
+            skew: 0,
+            total_long_size: 0,
+            total_short_size: 0,
+            last_funding_time: 0,
+            global_funding_index: 0,
+            funding_rate: 0,
+            premium_sum: 0,
+            premium_last: 0,
+            premium_last_sample: 0,
+            last_history_time: 0,
+            long_socialized_loss_index: 0,
+            short_socialized_loss_index: 0,
+            params: MarketParams {
+                initial_margin_requirement: 1_000,
+                maintenance_margin_requirement: 500,
+                max_leverage: 10,
+                skew_scale: 1_000_000_000,
+                max_funding_rate: 100,
+                liquidation_fee: 250,
+                liquidation_buffer: 1_000,
+                max_liquidation_fraction: 5_000,
+                max_price_age_secs: 60,
+                max_confidence_ratio: 100,
+                max_oracle_deviation: 200,
+                price_band: 1_000,
+                price_band_window_secs: 60,
+                circuit_breaker_cooldown_secs: 900,
+                twap_window_secs: 3_600,
+                funding_crank_reward: 0,
+                funding_model: FundingModel::Skew,
+                max_funding_velocity: 0,
+                interest_rate: 0,
+                premium_clamp: 0,
+                maker_fee: 0,
+                taker_fee: 0,
+                insurance_fee_share: 0,
+                max_position_size: 1_000_000_000_000,
+            },
+            bump: 0,
+            last_accepted_price: 0,
+            last_accepted_price_time: 0,
+            halted: false,
+            halted_at: 0,
+            cumulative_price: 0,
+            twap_last_price: 0,
+            twap_last_update: 0,
+            twap_observations: [TwapObservation::default(); TWAP_OBSERVATIONS],
+            twap_observation_index: 0,
+        }
+    }
+
+    pub(crate) fn user_account(balance: u64) -> UserAccount {
+        UserAccount {
+            owner: Pubkey::default(),
+            balance,
+            positions: Vec::new(),
+            last_faucet_time: 0,
+            volume_buckets: [0; VOLUME_WINDOW_DAYS],
+            volume_last_day: 0,
+            referral_code: Pubkey::default(),
+        }
+    }
+
+    #[test]
+    fn margin_price_is_the_worse_of_spot_and_twap() {
+        // $100 for an hour, then a crash to $60 that the TWAP lags behind
+        let mut market = market();
+        market.record_price(100_000_000, 1);
+        market.record_price(60_000_000, 3_601);
+        let now = 3_661;
+        let twap = market.twap(now).unwrap();
+        assert!(twap > 99_000_000 && twap < 100_000_000);
+
+        // A long is valued at the crashed spot price, a short at the lagging TWAP
+        assert_eq!(market.margin_price(60_000_000, 1, now), 60_000_000);
+        assert_eq!(market.margin_price(60_000_000, -1, now), twap);
+
+        // On a spike it is the other way round
+        assert_eq!(market.margin_price(150_000_000, 1, now), twap);
+        assert_eq!(market.margin_price(150_000_000, -1, now), 150_000_000);
+
+        // Without a price history there is only the spot price
+        assert_eq!(self::market().margin_price(60_000_000, -1, now), 60_000_000);
+    }
+
+    #[test]
+    fn crank_samples_at_most_once_per_checkpoint_spacing() {
+        // An hour long window is checkpointed every 514 seconds
+        let mut market = market();
+        assert!(market.sample_crank_price(100_000_000, 1));
+        assert!(!market.sample_crank_price(150_000_000, 514));
+        assert_eq!(market.twap_last_price, 100_000_000);
+
+        assert!(market.sample_crank_price(150_000_000, 515));
+        assert_eq!(market.twap_last_update, 515);
+
+        // A trade's price counts as the latest sample
+        market.record_price(120_000_000, 700);
+        assert!(!market.sample_crank_price(150_000_000, 1_000));
+        assert_eq!(market.twap_last_price, 120_000_000);
+        assert_eq!(market.twap_last_update, 700);
+    }
+
+    #[test]
+    fn price_band_halts_until_the_cooldown_ends() {
+        let mut market = market();
+        assert!(market.check_price_band(100_000_000, 0).unwrap());
+
+        // 10% within the window is accepted and becomes the new reference
+        assert!(market.check_price_band(110_000_000, 30).unwrap());
+        assert_eq!(market.last_accepted_price, 110_000_000);
+
+        // A jump of more than 10% halts the market and keeps the old reference
+        assert!(!market.check_price_band(125_000_000, 60).unwrap());
+        assert!(market.halted);
+        assert_eq!(market.halted_at, 60);
+        assert_eq!(market.last_accepted_price, 110_000_000);
+
+        // Every price is refused during the cooldown, even one back inside the band
+        assert!(market.check_price_band(110_000_000, 959).is_err());
+        assert!(market.is_halted(959));
+
+        // After the cooldown the market resumes on its own, with the first price as the reference
+        assert!(!market.is_halted(960));
+        assert!(market.check_price_band(125_000_000, 960).unwrap());
+        assert!(!market.halted);
+        assert_eq!(market.last_accepted_price, 125_000_000);
+    }
+
+    #[test]
+    fn price_band_only_compares_prices_within_the_window() {
+        let mut market = market();
+        assert!(market.check_price_band(100_000_000, 0).unwrap());
+
+        // Outside the window there is no reference to deviate from
+        assert!(market.check_price_band(150_000_000, 61).unwrap());
+        assert!(!market.halted);
+        assert_eq!(market.last_accepted_price_time, 61);
+    }
 }
@@ -1,28 +1,138 @@
 import * as anchor from "@coral-xyz/anchor";
 import { Program } from "@coral-xyz/anchor";
+import { createMint, getOrCreateAssociatedTokenAccount, mintTo } from "@solana/spl-token";
 import { expect } from "chai";
 import { BlankonContracts } from "../target/types/blankon_contracts";
 
-describe("blankon-contracts", () => {
-  // Configure the client to use the local cluster.
-  const provider = anchor.AnchorProvider.env();
-  anchor.setProvider(provider);
+const MARKETS = [
+  {
+    // Gold
+    pythPriceAccount: new anchor.web3.PublicKey("2uPQGpm8X4ZkxMHxrAW1QuhXcse1AHEgPih6Xp9NuEWW"),
+    feedId: "765d2ba906dbc32ca17cc11f5310a89e9ee1f6420508c63861f2f8ba4ee34bb2",
+  },
+  {
+    // SOL
+    pythPriceAccount: new anchor.web3.PublicKey("7UVimffxr9ow1uXYxsr4LHAcV58mLzhmwaeKvJ1pjLiE"),
+    feedId: "ef0d8b6fda2ceba41da15d4095d1da392a0d2f8ed0c6c7bc0f4cfac8c280b56d",
+  },
+  {
+    // BTC
+    pythPriceAccount: new anchor.web3.PublicKey("4cSM2e6rvbGQUFiJbqytoVMi5GgghSMr8LwVrT9VPSPo"),
+    feedId: "e62df6c8b4a85fe1a67db44dc12de5db330f7ac66b72dc658afedf0f4a415b43",
+  },
+];
 
-  const program = anchor.workspace.blankonContracts as Program<BlankonContracts>;
-  const blankonState = anchor.web3.Keypair.generate();
+// 10% initial margin, 5% maintenance margin, 10x leverage, 1% max funding rate, 2.5% liquidation fee,
+// 10% buffer above maintenance margin, at most half of a position closed per liquidation and
+// 0.02% / 0.05% maker / taker trading fees with 20% of them going to the insurance fund, and
+// positions of at most 1,000,000 units
+const MARKET_PARAMS = {
+  initialMarginRequirement: new anchor.BN(1_000),
+  maintenanceMarginRequirement: new anchor.BN(500),
+  maxLeverage: 10,
+  skewScale: new anchor.BN(1_000_000_000),
+  maxFundingRate: new anchor.BN(100),
+  liquidationFee: new anchor.BN(250),
+  liquidationBuffer: new anchor.BN(1_000),
+  maxLiquidationFraction: new anchor.BN(5_000),
+  // Cloned devnet price accounts are not refreshed while the tests run
+  maxPriceAgeSecs: new anchor.BN(86_400),
+  maxConfidenceRatio: new anchor.BN(500), // 5%
+  maxOracleDeviation: new anchor.BN(500),
+  priceBand: new anchor.BN(1_000),
+  priceBandWindowSecs: new anchor.BN(60),
+  circuitBreakerCooldownSecs: new anchor.BN(300),
+  twapWindowSecs: new anchor.BN(3_600),
+  fundingCrankReward: new anchor.BN(100_000),
+  fundingModel: { skew: {} },
+  maxFundingVelocity: new anchor.BN(0),
+  interestRate: new anchor.BN(1),
+  premiumClamp: new anchor.BN(5),
+  makerFee: new anchor.BN(2),
+  takerFee: new anchor.BN(5),
+  insuranceFeeShare: new anchor.BN(2_000),
+  maxPositionSize: new anchor.BN(1_000_000_000_000),
+};
+
+// $10,000 starting balance, $1,000 faucet top-ups at most once a day
+const PAPER_CONFIG = {
+  startingBalance: new anchor.BN(10_000_000_000),
+  faucetAmount: new anchor.BN(1_000_000_000),
+  faucetCooldown: new anchor.BN(86_400),
+};
+
+const provider = anchor.AnchorProvider.env();
+anchor.setProvider(provider);
+
+const program = anchor.workspace.blankonContracts as Program<BlankonContracts>;
+
+const userAccountPda = (blankonState: anchor.web3.PublicKey, user: anchor.web3.PublicKey) =>
+  anchor.web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("user-account"), blankonState.toBuffer(), user.toBuffer()],
+    program.programId
+  )[0];
+
+const marketPda = (blankonState: anchor.web3.PublicKey, marketIndex: number) => {
+  const index = Buffer.alloc(2);
+  index.writeUInt16LE(marketIndex);
+  return anchor.web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("market"), blankonState.toBuffer(), index],
+    program.programId
+  )[0];
+};
+
+const fallbackOraclePda = (market: anchor.web3.PublicKey) =>
+  anchor.web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("fallback-oracle"), market.toBuffer()],
+    program.programId
+  )[0];
+
+// Acceptable prices that never reject a fill, for trades that do not test slippage
+const ANY_BUY_PRICE = new anchor.BN("18446744073709551615");
+const ANY_SELL_PRICE = new anchor.BN(0);
+
+// Referral codes are fixed 32 byte strings, zero padded
+const referralCode = (code: string) => {
+  const bytes = Buffer.alloc(32);
+  bytes.write(code);
+  return bytes;
+};
 
-  const [userPda] = anchor.web3.PublicKey.findProgramAddressSync(
-    [Buffer.from("user-account"), provider.wallet.publicKey.toBuffer()],
+const referralCodePda = (blankonState: anchor.web3.PublicKey, code: Buffer) =>
+  anchor.web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("referral-code"), blankonState.toBuffer(), code],
     program.programId
-  );
+  )[0];
+
+// [market, pyth price account] pairs consumed through remaining accounts, followed by the
+// fallback price account for markets listed in `withFallback`
+const marketAccounts = (
+  blankonState: anchor.web3.PublicKey,
+  marketIndexes: number[],
+  withFallback: number[] = []
+) =>
+  marketIndexes.flatMap((marketIndex) => {
+    const market = marketPda(blankonState, marketIndex);
+    const accounts = [
+      { pubkey: market, isSigner: false, isWritable: false },
+      { pubkey: MARKETS[marketIndex].pythPriceAccount, isSigner: false, isWritable: false },
+    ];
+    if (withFallback.includes(marketIndex)) {
+      accounts.push({ pubkey: fallbackOraclePda(market), isSigner: false, isWritable: false });
+    }
+    return accounts;
+  });
+
+describe("blankon-contracts", () => {
+  const blankonState = anchor.web3.Keypair.generate();
+  const userPda = userAccountPda(blankonState.publicKey, provider.wallet.publicKey);
+  // Pushes prices to market 2's fallback oracle
+  const fallbackKeeper = anchor.web3.Keypair.generate();
 
   before(async () => {
+    // 20% of liquidation penalties go to the insurance fund
     const tx = await program.methods
-      .initialize(
-        new anchor.web3.PublicKey("2uPQGpm8X4ZkxMHxrAW1QuhXcse1AHEgPih6Xp9NuEWW"),
-        new anchor.web3.PublicKey("7UVimffxr9ow1uXYxsr4LHAcV58mLzhmwaeKvJ1pjLiE"),
-        new anchor.web3.PublicKey("4cSM2e6rvbGQUFiJbqytoVMi5GgghSMr8LwVrT9VPSPo")
-      )
+      .initialize(new anchor.BN(2_000), { paper: {} }, PAPER_CONFIG)
       .accounts({
         blankonState: blankonState.publicKey,
         admin: anchor.AnchorProvider.env().wallet.publicKey,
@@ -31,10 +141,27 @@ describe("blankon-contracts", () => {
       .rpc();
     console.log("Initialized");
 
+    for (const market of MARKETS) {
+      await program.methods
+        .addMarket(
+          market.pythPriceAccount,
+          Array.from(Buffer.from(market.feedId, "hex")),
+          MARKET_PARAMS
+        )
+        .accounts({
+          blankonState: blankonState.publicKey,
+          admin: provider.wallet.publicKey,
+        })
+        .rpc();
+    }
+    console.log("Markets added");
+
     const tx2 = await program.methods
       .createUserAccount()
       .accounts({
+        blankonState: blankonState.publicKey,
         user: provider.wallet.publicKey,
+        referralCode: null,
       })
       .rpc();
     console.log("User account created", tx);
@@ -48,17 +175,146 @@ describe("blankon-contracts", () => {
     expect(userAccount.balance.toString()).to.equal("10000000000");
   });
 
+  it("Creates an empty insurance fund", async () => {
+    const [insuranceFundPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("insurance-fund"), blankonState.publicKey.toBuffer()],
+      program.programId
+    );
+    const insuranceFund = await program.account.insuranceFund.fetch(insuranceFundPda);
+    expect(insuranceFund.balance.toNumber()).to.equal(0);
+
+    const state = await program.account.blankonState.fetch(blankonState.publicKey);
+    expect(state.insuranceFundShare.toNumber()).to.equal(2_000);
+    expect(state.cumulativeBadDebt.toNumber()).to.equal(0);
+    expect(state.cumulativeSocializedLoss.toNumber()).to.equal(0);
+  });
+
+  it("Adds markets", async () => {
+    const state = await program.account.blankonState.fetch(blankonState.publicKey);
+    expect(state.marketCount).to.equal(MARKETS.length);
+
+    const market = await program.account.marketInfo.fetch(marketPda(blankonState.publicKey, 2));
+    expect(market.marketIndex).to.equal(2);
+    expect(market.pythPriceAccount.toBase58()).to.equal(MARKETS[2].pythPriceAccount.toBase58());
+  });
+
+  it("Updates market params", async () => {
+    await program.methods
+      .updateMarketParams({ ...MARKET_PARAMS, maxLeverage: 5 })
+      .accounts({
+        blankonState: blankonState.publicKey,
+        market: marketPda(blankonState.publicKey, 2),
+        admin: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    const market = await program.account.marketInfo.fetch(marketPda(blankonState.publicKey, 2));
+    expect(market.params.maxLeverage).to.equal(5);
+
+    try {
+      await program.methods
+        .updateMarketParams({ ...MARKET_PARAMS, maintenanceMarginRequirement: new anchor.BN(2_000) })
+        .accounts({
+          blankonState: blankonState.publicKey,
+          market: marketPda(blankonState.publicKey, 2),
+          admin: provider.wallet.publicKey,
+        })
+        .rpc();
+      expect.fail("maintenance margin above initial margin should be rejected");
+    } catch (err) {
+      expect(err.error.errorCode.code).to.equal("InvalidMarketParams");
+    }
+  });
+
+  it("Switches a market to velocity funding", async () => {
+    const accounts = {
+      blankonState: blankonState.publicKey,
+      market: marketPda(blankonState.publicKey, 2),
+      admin: provider.wallet.publicKey,
+    };
+
+    try {
+      await program.methods
+        .updateMarketParams({ ...MARKET_PARAMS, maxLeverage: 5, fundingModel: { velocity: {} } })
+        .accounts(accounts)
+        .rpc();
+      expect.fail("velocity funding without a max velocity should be rejected");
+    } catch (err) {
+      expect(err.error.errorCode.code).to.equal("InvalidMarketParams");
+    }
+
+    await program.methods
+      .updateMarketParams({
+        ...MARKET_PARAMS,
+        maxLeverage: 5,
+        fundingModel: { velocity: {} },
+        maxFundingVelocity: new anchor.BN(300),
+      })
+      .accounts(accounts)
+      .rpc();
+
+    const market = await program.account.marketInfo.fetch(marketPda(blankonState.publicKey, 2));
+    expect(market.params.fundingModel).to.deep.equal({ velocity: {} });
+  });
+
   it("Opens position", async () => {
     const tx = await program.methods
-      .openPosition(0, new anchor.BN(100 * 1_000_000), 5)
+      .openPosition(new anchor.BN(100 * 1_000_000), 5, ANY_BUY_PRICE, null)
       .accounts({
-        user: provider.wallet.publicKey,
-        pythPriceAccount: new anchor.web3.PublicKey("2uPQGpm8X4ZkxMHxrAW1QuhXcse1AHEgPih6Xp9NuEWW"),
         blankonState: blankonState.publicKey,
+        market: marketPda(blankonState.publicKey, 0),
+        user: provider.wallet.publicKey,
+        pythPriceAccount: MARKETS[0].pythPriceAccount,
+        fallbackPriceAccount: null,
+        referralCode: null,
       })
       .rpc();
     console.log("Position opened", tx);
 
+    // The skew-adjusted fill is checked against the trader's bound and deadline, and the size
+    // against the market's maximum
+    for (const [size, acceptablePrice, expiry, error] of [
+      [new anchor.BN(1_000_000), new anchor.BN(1), null, "PriceSlippageExceeded"],
+      [new anchor.BN(1_000_000), ANY_BUY_PRICE, new anchor.BN(1), "OrderExpired"],
+      [MARKET_PARAMS.maxPositionSize.addn(1), ANY_BUY_PRICE, null, "PositionTooLarge"],
+    ] as const) {
+      try {
+        await program.methods
+          .openPosition(size, 2, acceptablePrice, expiry)
+          .accounts({
+            blankonState: blankonState.publicKey,
+            market: marketPda(blankonState.publicKey, 2),
+            user: provider.wallet.publicKey,
+            pythPriceAccount: MARKETS[2].pythPriceAccount,
+            fallbackPriceAccount: null,
+            referralCode: null,
+          })
+          .rpc();
+        expect.fail(`open should fail with ${error}`);
+      } catch (err) {
+        expect(err.error.errorCode.code).to.equal(error);
+      }
+    }
+
+    // The traded price becomes the circuit breaker's reference
+    const market = await program.account.marketInfo.fetch(marketPda(blankonState.publicKey, 0));
+    expect(market.lastAcceptedPrice.toNumber()).to.be.greaterThan(0);
+    expect(market.halted).to.equal(false);
+    // ...and the latest price of its TWAP accumulator
+    expect(market.twapLastPrice.toString()).to.equal(market.lastAcceptedPrice.toString());
+
+    // Opening against an empty market increases its skew and pays the taker fee
+    const state = await program.account.blankonState.fetch(blankonState.publicKey);
+    expect(state.feePool.toNumber()).to.be.greaterThan(0);
+    // ...with the market's share of it going to the insurance fund
+    const [insuranceFundPda] = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("insurance-fund"), blankonState.publicKey.toBuffer()],
+      program.programId
+    );
+    const insuranceFund = await program.account.insuranceFund.fetch(insuranceFundPda);
+    const tradeFee = state.feePool.toNumber() + insuranceFund.balance.toNumber();
+    expect(insuranceFund.balance.toNumber()).to.equal(Math.floor(tradeFee / 5));
+
     const userAccount = await program.account.userAccount.fetch(userPda);
     console.log("User account data: ", userAccount);
 
@@ -73,26 +329,570 @@ describe("blankon-contracts", () => {
       .accounts({
         user: provider.wallet.publicKey,
         blankonState: blankonState.publicKey,
-        pythPriceAccountGold: new anchor.web3.PublicKey("2uPQGpm8X4ZkxMHxrAW1QuhXcse1AHEgPih6Xp9NuEWW"),
-        pythPriceAccountSol: new anchor.web3.PublicKey("7UVimffxr9ow1uXYxsr4LHAcV58mLzhmwaeKvJ1pjLiE"),
-        pythPriceAccountBtc: new anchor.web3.PublicKey("4cSM2e6rvbGQUFiJbqytoVMi5GgghSMr8LwVrT9VPSPo"),
       })
+      .remainingAccounts(marketAccounts(blankonState.publicKey, [0]))
       .view();
 
     console.log("User status: ", tx2);
   });
 
+  it("Settles funding one market at a time or all at once", async () => {
+    await program.methods
+      .settleMarketFunding()
+      .accounts({
+        blankonState: blankonState.publicKey,
+        market: marketPda(blankonState.publicKey, 0),
+        userAccount: userPda,
+      })
+      .rpc();
+
+    await program.methods
+      .settleAllFunding()
+      .accounts({ blankonState: blankonState.publicKey, userAccount: userPda })
+      .remainingAccounts([
+        { pubkey: marketPda(blankonState.publicKey, 0), isSigner: false, isWritable: true },
+      ])
+      .rpc();
+
+    // Everything accrued has been settled
+    const status = await program.methods
+      .getUserStatus()
+      .accounts({ blankonState: blankonState.publicKey, user: provider.wallet.publicKey })
+      .remainingAccounts(marketAccounts(blankonState.publicKey, [0]))
+      .view();
+    expect(Math.abs(status.positionStatus[0].accruedFunding.toNumber())).to.be.lessThan(1_000);
+  });
+
+  it("Rejects liquidation of a healthy account", async () => {
+    const keeper = anchor.web3.Keypair.generate();
+    await provider.connection.confirmTransaction(
+      await provider.connection.requestAirdrop(keeper.publicKey, anchor.web3.LAMPORTS_PER_SOL),
+      "confirmed"
+    );
+    await program.methods
+      .createUserAccount()
+      .accounts({ blankonState: blankonState.publicKey, user: keeper.publicKey, referralCode: null })
+      .signers([keeper])
+      .rpc();
+
+    try {
+      await program.methods
+        .liquidate(0)
+        .accounts({
+          blankonState: blankonState.publicKey,
+          userAccount: userPda,
+          keeper: keeper.publicKey,
+        })
+        .remainingAccounts(marketAccounts(blankonState.publicKey, [0]).map((meta) => ({ ...meta, isWritable: true })))
+        .signers([keeper])
+        .rpc();
+      expect.fail("healthy account should not be liquidatable");
+    } catch (err) {
+      expect(err.error.errorCode.code).to.equal("NotLiquidatable");
+    }
+  });
+
+  it("Tops up paper balance from the faucet once per cooldown", async () => {
+    const before = await program.account.userAccount.fetch(userPda);
+
+    await program.methods
+      .faucetTopUp()
+      .accounts({ blankonState: blankonState.publicKey, user: provider.wallet.publicKey })
+      .rpc();
+
+    const after = await program.account.userAccount.fetch(userPda);
+    expect(after.balance.sub(before.balance).toString()).to.equal(PAPER_CONFIG.faucetAmount.toString());
+
+    try {
+      await program.methods
+        .faucetTopUp()
+        .accounts({ blankonState: blankonState.publicKey, user: provider.wallet.publicKey })
+        .rpc();
+      expect.fail("second top-up within the cooldown should be rejected");
+    } catch (err) {
+      expect(err.error.errorCode.code).to.equal("FaucetCooldown");
+    }
+  });
+
+  it("Lets anyone crank funding", async () => {
+    const market = marketPda(blankonState.publicKey, 1);
+    const crank = async () => {
+      const cranker = anchor.web3.Keypair.generate();
+      await program.methods
+        .calculateFunding()
+        .accounts({
+          blankonState: blankonState.publicKey,
+          market,
+          cranker: cranker.publicKey,
+          crankerAccount: null,
+          pythPriceAccount: MARKETS[1].pythPriceAccount,
+          fallbackPriceAccount: null,
+        })
+        .signers([cranker])
+        .rpc();
+      return program.account.marketInfo.fetch(market);
+    };
+
+    // The crank feeds the TWAP accumulator, but at most once per checkpoint spacing
+    const first = await crank();
+    expect(first.twapLastUpdate.toNumber()).to.be.greaterThan(0);
+    const second = await crank();
+    expect(second.twapLastUpdate.toNumber()).to.equal(first.twapLastUpdate.toNumber());
+  });
+
+  it("Returns the funding history of a market", async () => {
+    const market = marketPda(blankonState.publicKey, 1);
+    const fundingHistory = anchor.web3.PublicKey.findProgramAddressSync(
+      [Buffer.from("funding-history"), market.toBuffer()],
+      program.programId
+    )[0];
+
+    const history = await program.methods.getFundingHistory(0).accounts({ fundingHistory }).view();
+
+    // Funding is not due yet on a freshly added market, so nothing was recorded
+    expect(history.market.toBase58()).to.equal(market.toBase58());
+    expect(history.totalRecords.toNumber()).to.equal(0);
+    expect(history.records).to.have.length(0);
+  });
+
+  it("Discounts fees by rolling volume tier", async () => {
+    const tier = (minVolume: number, discount: number) => ({
+      minVolume: new anchor.BN(minVolume),
+      discount: new anchor.BN(discount),
+    });
+
+    // Discounts must not shrink as the required volume grows
+    try {
+      await program.methods
+        .updateFeeTiers([tier(0, 0), tier(0, 0), tier(1, 2_000), tier(1_000_000, 1_000)])
+        .accounts({ blankonState: blankonState.publicKey, admin: provider.wallet.publicKey })
+        .rpc();
+      expect.fail("fee tiers out of order should be rejected");
+    } catch (err) {
+      expect(err.error.errorCode.code).to.equal("InvalidConfiguration");
+    }
+
+    // 10% off from the first traded unit, 20% off past a billion dollars
+    await program.methods
+      .updateFeeTiers([tier(0, 0), tier(0, 0), tier(1, 1_000), tier(1_000_000_000_000_000, 2_000)])
+      .accounts({ blankonState: blankonState.publicKey, admin: provider.wallet.publicKey })
+      .rpc();
+
+    // The position opened earlier counts towards the rolling volume
+    const status = await program.methods
+      .getUserStatus()
+      .accounts({
+        user: provider.wallet.publicKey,
+        blankonState: blankonState.publicKey,
+      })
+      .remainingAccounts(marketAccounts(blankonState.publicKey, [0]))
+      .view();
+    expect(status.rollingVolume.toNumber()).to.be.greaterThan(0);
+    expect(status.feeDiscount.toNumber()).to.equal(1_000);
+  });
+
+  it("Shares a referred user's fees with the referrer", async () => {
+    const code = referralCode("BLANKON");
+    const codePda = referralCodePda(blankonState.publicKey, code);
+
+    await program.methods
+      .registerReferralCode(Array.from(code))
+      .accounts({ blankonState: blankonState.publicKey, referrer: provider.wallet.publicKey })
+      .rpc();
+
+    // Referrers earn 20% of their referees' fees, referees get 10% off
+    await program.methods
+      .updateReferralConfig({ referrerShare: new anchor.BN(2_000), refereeDiscount: new anchor.BN(1_000) })
+      .accounts({ blankonState: blankonState.publicKey, admin: provider.wallet.publicKey })
+      .rpc();
+
+    const referee = anchor.web3.Keypair.generate();
+    await provider.connection.confirmTransaction(
+      await provider.connection.requestAirdrop(referee.publicKey, anchor.web3.LAMPORTS_PER_SOL),
+      "confirmed"
+    );
+    await program.methods
+      .createUserAccount()
+      .accounts({ blankonState: blankonState.publicKey, user: referee.publicKey, referralCode: codePda })
+      .signers([referee])
+      .rpc();
+
+    const refereeAccount = await program.account.userAccount.fetch(
+      userAccountPda(blankonState.publicKey, referee.publicKey)
+    );
+    expect(refereeAccount.referralCode.toBase58()).to.equal(codePda.toBase58());
+
+    const tradeAccounts = {
+      blankonState: blankonState.publicKey,
+      market: marketPda(blankonState.publicKey, 1),
+      user: referee.publicKey,
+      pythPriceAccount: MARKETS[1].pythPriceAccount,
+      fallbackPriceAccount: null,
+      referralCode: null,
+    };
+
+    // Trades of a referred user cannot leave out the referrer
+    try {
+      await program.methods
+        .openPosition(new anchor.BN(1_000_000), 2, ANY_BUY_PRICE, null)
+        .accounts(tradeAccounts)
+        .signers([referee])
+        .rpc();
+      expect.fail("referred trades should require the referral code");
+    } catch (err) {
+      expect(err.error.errorCode.code).to.equal("InvalidReferralCode");
+    }
+
+    await program.methods
+      .openPosition(new anchor.BN(1_000_000), 2, ANY_BUY_PRICE, null)
+      .accounts({ ...tradeAccounts, referralCode: codePda })
+      .signers([referee])
+      .rpc();
+
+    const referral = await program.account.referralCode.fetch(codePda);
+    expect(referral.referredUsers.toNumber()).to.equal(1);
+    expect(referral.claimableRewards.toNumber()).to.be.greaterThan(0);
+
+    const before = await program.account.userAccount.fetch(userPda);
+    await program.methods
+      .claimReferralRewards()
+      .accounts({
+        blankonState: blankonState.publicKey,
+        referralCode: codePda,
+        referrer: provider.wallet.publicKey,
+      })
+      .rpc();
+    const after = await program.account.userAccount.fetch(userPda);
+    expect(after.balance.sub(before.balance).toNumber()).to.equal(referral.claimableRewards.toNumber());
+  });
+
+  it("Adds a fallback oracle pushed by a keeper", async () => {
+    const market = marketPda(blankonState.publicKey, 2);
+    const fallbackPriceAccount = fallbackOraclePda(market);
+
+    await program.methods
+      .addFallbackOracle(fallbackKeeper.publicKey)
+      .accounts({
+        blankonState: blankonState.publicKey,
+        market,
+        admin: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    const marketInfo = await program.account.marketInfo.fetch(market);
+    expect(marketInfo.fallbackOracle.toBase58()).to.equal(fallbackPriceAccount.toBase58());
+
+    try {
+      await program.methods
+        .pushPrice(new anchor.BN(100_000_000), new anchor.BN(10_000))
+        .accounts({ fallbackPriceAccount, authority: provider.wallet.publicKey })
+        .rpc();
+      expect.fail("only the keeper may push prices");
+    } catch (err) {
+      expect(err.error.errorCode.code).to.equal("UnauthorizedAccess");
+    }
+
+    await program.methods
+      .pushPrice(new anchor.BN(100_000_000), new anchor.BN(10_000))
+      .accounts({ fallbackPriceAccount, authority: fallbackKeeper.publicKey })
+      .signers([fallbackKeeper])
+      .rpc();
+
+    const pushed = await program.account.pushedPrice.fetch(fallbackPriceAccount);
+    expect(pushed.price.toNumber()).to.equal(100_000_000);
+
+    // Once configured, the fallback must accompany the market
+    try {
+      await program.methods
+        .getMarketStatus()
+        .accounts({ blankonState: blankonState.publicKey })
+        .remainingAccounts(marketAccounts(blankonState.publicKey, [2]))
+        .view();
+      expect.fail("market without its fallback oracle should be rejected");
+    } catch (err) {
+      expect(err.toString()).to.contain("InvalidOracleAccount");
+    }
+  });
+
+  it("Halts a market whose price leaves the band until the admin resumes it", async () => {
+    const market = marketPda(blankonState.publicKey, 2);
+    const fallbackPriceAccount = fallbackOraclePda(market);
+    const adminAccounts = {
+      blankonState: blankonState.publicKey,
+      market,
+      admin: provider.wallet.publicKey,
+    };
+    const { params } = await program.account.marketInfo.fetch(market);
+
+    // Treat the cloned Pyth price as stale so the keeper's pushes are the market price, and
+    // compare them against a reference that stays in the window for the whole test
+    await program.methods
+      .updateMarketParams({
+        ...params,
+        maxPriceAgeSecs: new anchor.BN(5),
+        priceBandWindowSecs: new anchor.BN(3_600),
+      })
+      .accounts(adminAccounts)
+      .rpc();
+    await program.methods.resumeMarket().accounts(adminAccounts).rpc();
+
+    const pushPrice = (price: number) =>
+      program.methods
+        .pushPrice(new anchor.BN(price), new anchor.BN(0))
+        .accounts({ fallbackPriceAccount, authority: fallbackKeeper.publicKey })
+        .signers([fallbackKeeper])
+        .rpc();
+    const openPosition = () =>
+      program.methods
+        .openPosition(new anchor.BN(1_000_000), 2, ANY_BUY_PRICE, null)
+        .accounts({
+          blankonState: blankonState.publicKey,
+          market,
+          user: provider.wallet.publicKey,
+          pythPriceAccount: MARKETS[2].pythPriceAccount,
+          fallbackPriceAccount,
+          referralCode: null,
+        })
+        .rpc();
+
+    // The crank accepts $100 as the reference
+    const cranker = anchor.web3.Keypair.generate();
+    await pushPrice(100_000_000);
+    await program.methods
+      .calculateFunding()
+      .accounts({
+        blankonState: blankonState.publicKey,
+        market,
+        cranker: cranker.publicKey,
+        crankerAccount: null,
+        pythPriceAccount: MARKETS[2].pythPriceAccount,
+        fallbackPriceAccount,
+      })
+      .signers([cranker])
+      .rpc();
+    let marketInfo = await program.account.marketInfo.fetch(market);
+    expect(marketInfo.lastAcceptedPrice.toNumber()).to.equal(100_000_000);
+
+    // A 50% jump halts the market. The trade that saw it succeeds without opening anything so
+    // that the halt persists.
+    await pushPrice(150_000_000);
+    const before = await program.account.userAccount.fetch(userPda);
+    await openPosition();
+    const after = await program.account.userAccount.fetch(userPda);
+    expect(after.positions).to.deep.equal(before.positions);
+
+    marketInfo = await program.account.marketInfo.fetch(market);
+    expect(marketInfo.halted).to.equal(true);
+    expect(marketInfo.lastAcceptedPrice.toNumber()).to.equal(100_000_000);
+
+    // Until the cooldown ends, even a price back inside the band is refused
+    await pushPrice(100_000_000);
+    try {
+      await openPosition();
+      expect.fail("trading a halted market should fail");
+    } catch (err) {
+      expect(err.error.errorCode.code).to.equal("MarketHalted");
+    }
+
+    const outsider = anchor.web3.Keypair.generate();
+    try {
+      await program.methods
+        .resumeMarket()
+        .accounts({ ...adminAccounts, admin: outsider.publicKey })
+        .signers([outsider])
+        .rpc();
+      expect.fail("non-admin should not resume markets");
+    } catch (err) {
+      expect(err.error.errorCode.code).to.equal("UnauthorizedAccess");
+    }
+
+    await program.methods.resumeMarket().accounts(adminAccounts).rpc();
+    marketInfo = await program.account.marketInfo.fetch(market);
+    expect(marketInfo.halted).to.equal(false);
+    expect(marketInfo.lastAcceptedPrice.toNumber()).to.equal(0);
+
+    await program.methods.updateMarketParams(params).accounts(adminAccounts).rpc();
+  });
+
   it("Can get market", async () => {
     const data = await program.methods
       .getMarketStatus()
       .accounts({
         blankonState: blankonState.publicKey,
-        pythPriceAccountGold: new anchor.web3.PublicKey("2uPQGpm8X4ZkxMHxrAW1QuhXcse1AHEgPih6Xp9NuEWW"),
-        pythPriceAccountSol: new anchor.web3.PublicKey("7UVimffxr9ow1uXYxsr4LHAcV58mLzhmwaeKvJ1pjLiE"),
-        pythPriceAccountBtc: new anchor.web3.PublicKey("4cSM2e6rvbGQUFiJbqytoVMi5GgghSMr8LwVrT9VPSPo"),
       })
+      .remainingAccounts(marketAccounts(blankonState.publicKey, [0, 1, 2], [2]))
       .view();
 
     console.log("Market status: ", data);
+
+    for (const snapshot of data.marketSnapshots) {
+      expect(snapshot.oracleConfidenceTooWide).to.equal(
+        snapshot.oracleConfidenceRatio.gt(MARKET_PARAMS.maxConfidenceRatio)
+      );
+    }
+  });
+});
+
+describe("blankon-contracts collateralized mode", () => {
+  const blankonState = anchor.web3.Keypair.generate();
+  const user = anchor.web3.Keypair.generate();
+  const userPda = userAccountPda(blankonState.publicKey, user.publicKey);
+  const payer = (provider.wallet as anchor.Wallet).payer;
+
+  let collateralMint: anchor.web3.PublicKey;
+  let userTokenAccount: anchor.web3.PublicKey;
+
+  before(async () => {
+    await program.methods
+      .initialize(new anchor.BN(2_000), { collateralized: {} }, PAPER_CONFIG)
+      .accounts({
+        blankonState: blankonState.publicKey,
+        admin: provider.wallet.publicKey,
+      })
+      .signers([blankonState])
+      .rpc();
+
+    await program.methods
+      .addMarket(MARKETS[0].pythPriceAccount, Array.from(Buffer.from(MARKETS[0].feedId, "hex")), MARKET_PARAMS)
+      .accounts({
+        blankonState: blankonState.publicKey,
+        admin: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    await provider.connection.confirmTransaction(
+      await provider.connection.requestAirdrop(user.publicKey, anchor.web3.LAMPORTS_PER_SOL),
+      "confirmed"
+    );
+
+    collateralMint = await createMint(provider.connection, payer, payer.publicKey, null, 6);
+    userTokenAccount = (
+      await getOrCreateAssociatedTokenAccount(provider.connection, payer, collateralMint, user.publicKey)
+    ).address;
+    await mintTo(provider.connection, payer, collateralMint, userTokenAccount, payer, 1_000_000_000);
+
+    await program.methods
+      .initializeVault()
+      .accounts({
+        blankonState: blankonState.publicKey,
+        collateralMint,
+        admin: provider.wallet.publicKey,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    await program.methods
+      .createUserAccount()
+      .accounts({ blankonState: blankonState.publicKey, user: user.publicKey, referralCode: null })
+      .signers([user])
+      .rpc();
+  });
+
+  it("Creates user account without free balance", async () => {
+    const userAccount = await program.account.userAccount.fetch(userPda);
+    expect(userAccount.balance.toNumber()).to.equal(0);
+
+    try {
+      await program.methods
+        .faucetTopUp()
+        .accounts({ blankonState: blankonState.publicKey, user: user.publicKey })
+        .signers([user])
+        .rpc();
+      expect.fail("faucet should be disabled in collateralized mode");
+    } catch (err) {
+      expect(err.error.errorCode.code).to.equal("InvalidTradingMode");
+    }
+  });
+
+  it("Deposits and withdraws collateral", async () => {
+    await program.methods
+      .deposit(new anchor.BN(500_000_000))
+      .accounts({
+        blankonState: blankonState.publicKey,
+        user: user.publicKey,
+        collateralMint,
+        userTokenAccount,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+      })
+      .signers([user])
+      .rpc();
+
+    await program.methods
+      .withdraw(new anchor.BN(200_000_000))
+      .accounts({
+        blankonState: blankonState.publicKey,
+        user: user.publicKey,
+        collateralMint,
+        userTokenAccount,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+      })
+      .signers([user])
+      .rpc();
+
+    const userAccount = await program.account.userAccount.fetch(userPda);
+    expect(userAccount.balance.toNumber()).to.equal(300_000_000);
+
+    const tokenBalance = await provider.connection.getTokenAccountBalance(userTokenAccount);
+    expect(tokenBalance.value.amount).to.equal("700000000");
+  });
+
+  it("Collects trading fees into the admin's token account", async () => {
+    const tradeAccounts = {
+      blankonState: blankonState.publicKey,
+      market: marketPda(blankonState.publicKey, 0),
+      user: user.publicKey,
+      pythPriceAccount: MARKETS[0].pythPriceAccount,
+      fallbackPriceAccount: null,
+      referralCode: null,
+    };
+    await program.methods
+      .openPosition(new anchor.BN(1_000_000), 2, ANY_BUY_PRICE, null)
+      .accounts(tradeAccounts)
+      .signers([user])
+      .rpc();
+    await program.methods.closePosition(ANY_SELL_PRICE, null).accounts(tradeAccounts).signers([user]).rpc();
+
+    const state = await program.account.blankonState.fetch(blankonState.publicKey);
+    const fees = state.feePool.toNumber();
+    expect(fees).to.be.greaterThan(0);
+
+    const feeDestination = (
+      await getOrCreateAssociatedTokenAccount(provider.connection, payer, collateralMint, payer.publicKey)
+    ).address;
+
+    try {
+      await program.methods
+        .collectFees()
+        .accounts({
+          blankonState: blankonState.publicKey,
+          admin: user.publicKey,
+          collateralMint,
+          feeDestination,
+          tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+        })
+        .signers([user])
+        .rpc();
+      expect.fail("only the admin may collect fees");
+    } catch (err) {
+      expect(err.error.errorCode.code).to.equal("UnauthorizedAccess");
+    }
+
+    await program.methods
+      .collectFees()
+      .accounts({
+        blankonState: blankonState.publicKey,
+        admin: provider.wallet.publicKey,
+        collateralMint,
+        feeDestination,
+        tokenProgram: anchor.utils.token.TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+    const collected = await program.account.blankonState.fetch(blankonState.publicKey);
+    expect(collected.feePool.toNumber()).to.equal(0);
+    expect(collected.totalFeesCollected.toNumber()).to.equal(fees);
+
+    const tokenBalance = await provider.connection.getTokenAccountBalance(feeDestination);
+    expect(tokenBalance.value.amount).to.equal(fees.toString());
   });
 });